@@ -0,0 +1,134 @@
+use crate::errors::VpkError;
+
+#[cfg(not(feature = "std"))]
+use crate::io::Read;
+#[cfg(feature = "std")]
+use std::io::{self, Read};
+
+/// Read big endian bits from a byte source.
+///
+/// Bytes are pulled from the underlying reader one at a time, and only when
+/// the bits are needed. This means that the reader is never advanced past the
+/// last bit that was actually consumed.
+#[derive(Debug)]
+pub(crate) struct BitReader<R> {
+    rdr: R,
+    /// buffered bits that have been read from `rdr`, but not consumed
+    acc: u64,
+    /// the number of unconsumed bits in the low bits of `acc`
+    bits: u32,
+    /// total number of bytes read from `rdr`
+    read: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    pub(crate) fn new(rdr: R) -> Self {
+        Self {
+            rdr,
+            acc: 0,
+            bits: 0,
+            read: 0,
+        }
+    }
+
+    /// Get back the source, which is positioned after the last partially consumed byte
+    pub(crate) fn into_inner(self) -> R {
+        self.rdr
+    }
+
+    /// The number of bits that have been consumed
+    pub(crate) fn position(&self) -> u64 {
+        self.read * 8 - self.bits as u64
+    }
+
+    pub(crate) fn read_bit(&mut self) -> Result<bool, VpkError> {
+        self.read(1).map(|b| b == 1)
+    }
+
+    /// Read an unsigned value of up to 32 bits
+    pub(crate) fn read(&mut self, n: u32) -> Result<u32, VpkError> {
+        debug_assert!(n <= 32, "tried to read {} bits into a u32", n);
+        while self.bits < n {
+            self.fill_byte()?;
+        }
+        let val = (self.acc >> (self.bits - n)) & mask(n);
+        self.consume(n);
+
+        Ok(val as u32)
+    }
+
+    pub(crate) fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), VpkError> {
+        for byte in buf.iter_mut() {
+            *byte = self.read(8)? as u8;
+        }
+
+        Ok(())
+    }
+
+    /// Skip to the start of the next byte, and return the skipped bits
+    pub(crate) fn byte_align(&mut self) -> u8 {
+        let skip = self.bits % 8;
+        let skipped = (self.acc >> (self.bits - skip)) & mask(skip);
+        self.consume(skip);
+
+        skipped as u8
+    }
+
+    /// Look at the next `n` buffered bits without consuming them or reading from the source.
+    /// If there are less than `n` bits buffered, the missing low bits are zero.
+    /// Returns the bits and how many of them are real.
+    pub(crate) fn peek_buffered(&self, n: u32) -> (usize, u32) {
+        if self.bits >= n {
+            let val = (self.acc >> (self.bits - n)) & mask(n);
+            (val as usize, n)
+        } else {
+            let val = (self.acc << (n - self.bits)) & mask(n);
+            (val as usize, self.bits)
+        }
+    }
+
+    /// Drop `n` bits that were previously looked at with [`peek_buffered`](Self::peek_buffered)
+    pub(crate) fn consume(&mut self, n: u32) {
+        debug_assert!(n <= self.bits);
+        self.bits -= n;
+        self.acc &= mask(self.bits);
+    }
+
+    /// Buffer another byte from the source
+    pub(crate) fn fill_byte(&mut self) -> Result<(), VpkError> {
+        debug_assert!(self.bits <= 56);
+        let byte = read_byte(&mut self.rdr)?;
+        self.acc = (self.acc << 8) | byte as u64;
+        self.bits += 8;
+        self.read += 1;
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn mask(n: u32) -> u64 {
+    (1u64 << n) - 1
+}
+
+#[cfg(feature = "std")]
+fn read_byte<R: Read>(rdr: &mut R) -> Result<u8, VpkError> {
+    let mut byte = [0u8];
+    loop {
+        match rdr.read(&mut byte) {
+            Ok(0) => return Err(VpkError::UnexpectedEof),
+            Ok(_) => return Ok(byte[0]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+fn read_byte<R: Read>(rdr: &mut R) -> Result<u8, VpkError> {
+    let mut byte = [0u8];
+    match rdr.read(&mut byte)? {
+        0 => Err(VpkError::UnexpectedEof),
+        _ => Ok(byte[0]),
+    }
+}
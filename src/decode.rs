@@ -1,13 +1,30 @@
-use crate::errors::VpkError;
-use crate::format::{VpkHeader, VpkMethod, VpkTree};
-use bitstream_io::{BigEndian, BitReader};
+use crate::bits::BitReader;
+use crate::errors::{ErrorLocation, TreeKind, VpkError};
+use crate::format::{
+    HuffmanTree, HuffmanTrees, OffsetSample, Token, VpkHeader, VpkMethod, VpkTree,
+};
+use alloc::{string::String, string::ToString, vec::Vec};
+
+#[cfg(not(feature = "std"))]
+use crate::io::{Read, Write};
+#[cfg(feature = "std")]
 use std::{
-    collections::BTreeMap,
     fs::File,
     io::{BufReader, Cursor, Read, Write},
     path::Path,
 };
 
+mod diff;
+mod events;
+#[cfg(feature = "std")]
+mod reader;
+
+pub use self::diff::{diff, BitCosts, DiffToken, TokenDifference, VpkDiff};
+pub use self::events::DecodeEvent;
+use self::events::{Callback, EventSink, NoEvents, TextLog};
+#[cfg(feature = "std")]
+pub use self::reader::VpkReader;
+
 type LogWtr<'a> = &'a mut dyn Write;
 // [offset, length]
 type RawTrees = [VpkTree; 2];
@@ -39,6 +56,45 @@ impl From<&RawTrees> for TreeInfo {
     }
 }
 
+/// The output of [`Decoder::decode_partial`]
+#[derive(Debug)]
+pub struct PartialDecode {
+    /// All of the data decoded before an error
+    pub data: Vec<u8>,
+    /// The error that stopped decoding, or `None` if all of the data was decoded
+    pub error: Option<VpkError>,
+    /// Number of bits of compressed input read when decoding stopped
+    pub bit_offset: u64,
+}
+
+impl PartialDecode {
+    /// Check if all of the data was decoded without an error
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Convert into a `Result`, discarding the decoded data if there was an error
+    #[inline]
+    pub fn into_result(self) -> Result<Vec<u8>, VpkError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.data),
+        }
+    }
+}
+
+/// The output of [`Decoder::decode_tokens`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedTokens {
+    pub header: VpkHeader,
+    pub trees: HuffmanTrees,
+    /// The LZSS literals and copy-backs in the order they are stored in the file
+    pub tokens: Vec<Token>,
+    /// The decompressed data
+    pub data: Vec<u8>,
+}
+
 /// Specify the decoding settings, such as logging, input, and output.
 ///
 /// To create a new `Decoder`, use [`for_reader()`], [`for_bytes()`], or
@@ -72,40 +128,319 @@ impl From<&RawTrees> for TreeInfo {
 /// [`header()`]: Decoder::header
 /// [`trees()`]: Decoder::trees
 pub struct Decoder<'a, R: Read> {
-    src: BitReader<R, BigEndian>,
+    src: BitReader<R>,
     log: Option<LogWtr<'a>>,
     info: Option<(VpkHeader, RawTrees)>,
+    opts: Options,
 }
 
 impl<'a, R: Read> Decoder<'a, R> {
     #[inline]
     pub fn for_reader(rdr: R) -> Self {
         Self {
-            src: BitReader::endian(rdr, BigEndian),
+            src: BitReader::new(rdr),
             log: None,
             info: None,
+            opts: Options::default(),
         }
     }
 
+    #[cfg(feature = "std")]
     #[inline]
     pub fn with_logging<W: Write>(&mut self, wtr: &'a mut W) -> &mut Self {
         self.log = Some(wtr as LogWtr);
         self
     }
 
+    /// Return [`VpkError::OutputTooLarge`] instead of decoding more than `max` bytes.
+    ///
+    /// The [`VpkHeader::size`] is checked before any output is allocated,
+    /// and the decoded output is checked as it is decoded.
+    #[inline]
+    pub fn max_output_size(&mut self, max: usize) -> &mut Self {
+        self.opts.max_size = Some(max);
+        self
+    }
+
+    /// Return [`VpkError::RatioExceeded`] if more than `ratio` bytes are decoded for
+    /// each byte of compressed input.
+    ///
+    /// The ratio is checked as data is decoded, against the number of compressed bytes
+    /// read so far (including the header and trees).
+    #[inline]
+    pub fn max_ratio(&mut self, ratio: u32) -> &mut Self {
+        self.opts.max_ratio = Some(ratio);
+        self
+    }
+
+    /// Grow the output `Vec` as data is decoded, instead of allocating
+    /// [`VpkHeader::size`] bytes before decoding.
+    /// This is useful along with [`max_ratio()`](Decoder::max_ratio)
+    /// to avoid trusting the header of untrusted data.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder};
+    /// # let compressed = Encoder::for_bytes(b"ABBACABBACD").encode_to_vec().unwrap();
+    /// let decompressed = Decoder::for_bytes(&compressed)
+    ///     .max_output_size(1 << 20)
+    ///     .max_ratio(64)
+    ///     .lazy_allocation(true)
+    ///     .decode()
+    ///     .unwrap();
+    /// ```
+    #[inline]
+    pub fn lazy_allocation(&mut self, lazy: bool) -> &mut Self {
+        self.opts.lazy = lazy;
+        self
+    }
+
+    /// Reject `vpk0` data that other decoders, like the ones in HAL's games or the e-Reader,
+    /// might reject or mishandle. In strict mode, decoding returns an error for:
+    /// * a final copy-back that goes past [`VpkHeader::size`] ([`VpkError::CopyOvershoot`])
+    /// * non-zero padding bits after the last token ([`VpkError::NonZeroPadding`])
+    /// * any data after the `vpk0` stream ([`VpkError::TrailingData`])
+    /// * tree leaves wider than 32 bits ([`VpkError::LeafTooWide`])
+    /// * trees with more entries than one leaf for each bit size ([`VpkError::TooManyTreeEntries`])
+    #[inline]
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.opts.strict = strict;
+        self
+    }
+
     #[inline]
     pub fn header(&mut self) -> Result<VpkHeader, VpkError> {
         self.get_file_info().map(|(hdr, _)| *hdr)
     }
 
+    /// Get the text representations of the offsets and lengths trees.
+    /// Use [`huffman_trees()`](Decoder::huffman_trees) to get them as [`HuffmanTree`]s instead.
     #[inline]
     pub fn trees(&mut self) -> Result<TreeInfo, VpkError> {
         self.get_file_info().map(|(_, t)| t.into())
     }
 
+    /// Get the offsets and lengths trees as [`HuffmanTree`]s.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder};
+    /// # let compressed = Encoder::for_bytes(b"ABBACABBACD").encode_to_vec().unwrap();
+    /// let trees = Decoder::for_bytes(&compressed).huffman_trees().unwrap();
+    /// for leaf in trees.offsets.leaves() {
+    ///     println!("{:0w$b} => {} bits", leaf.code, leaf.bit_size, w = leaf.code_len as usize);
+    /// }
+    /// ```
+    /// Trees with codes longer than [`HuffmanTree::MAX_CODE_LEN`] bits
+    /// return [`VpkError::TreeTooDeep`].
+    pub fn huffman_trees(&mut self) -> Result<HuffmanTrees, VpkError> {
+        let [offsets, lengths] = &self.get_file_info()?.1;
+        let offsets =
+            HuffmanTree::try_from_vpk(offsets.clone()).map_err(|e| e.in_tree(TreeKind::Offsets))?;
+        let lengths =
+            HuffmanTree::try_from_vpk(lengths.clone()).map_err(|e| e.in_tree(TreeKind::Lengths))?;
+
+        Ok(HuffmanTrees { offsets, lengths })
+    }
+
     #[inline]
     pub fn decode(&mut self) -> Result<Vec<u8>, VpkError> {
-        do_decode(self)
+        let mut output = self.output_vec()?;
+        self.decode_to(&mut output)?;
+
+        Ok(output)
+    }
+
+    /// Decode as much data as possible, and keep the decoded data if there is an error.
+    ///
+    /// This is meant for recovering data from corrupt or truncated `vpk0` files.
+    /// Instead of discarding the output, the returned [`PartialDecode`] has all of the bytes
+    /// decoded before the error, along with the error and where decoding stopped.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder};
+    /// # let original = b"ABBACABBACDABBACABBACD";
+    /// # let compressed = Encoder::for_bytes(original).encode_to_vec().unwrap();
+    /// let truncated = &compressed[..compressed.len() - 1];
+    /// let partial = Decoder::for_bytes(truncated).decode_partial();
+    /// assert!(partial.error.is_some());
+    /// assert!(original.starts_with(&partial.data));
+    /// ```
+    pub fn decode_partial(&mut self) -> PartialDecode {
+        let mut data = Vec::new();
+        let result = self.output_vec().and_then(|output| {
+            data = output;
+            self.decode_to(&mut data)
+        });
+
+        PartialDecode {
+            data,
+            error: result.err(),
+            bit_offset: self.src.position(),
+        }
+    }
+
+    /// Decode into a `Vec<u8>`, and pass each [`DecodeEvent`] to `f` as it happens.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder, DecodeEvent};
+    /// # let compressed = Encoder::for_bytes(b"ABBACABBACD").encode_to_vec().unwrap();
+    /// let mut copies = 0;
+    /// Decoder::for_bytes(&compressed)
+    ///     .decode_with_events(|event| {
+    ///         if let DecodeEvent::CopyBack { length, .. } = event {
+    ///             copies += length;
+    ///         }
+    ///     })
+    ///     .unwrap();
+    /// assert_eq!(copies, 5);
+    /// ```
+    pub fn decode_with_events<F>(&mut self, f: F) -> Result<Vec<u8>, VpkError>
+    where
+        F: FnMut(DecodeEvent<'_>),
+    {
+        let mut output = self.output_vec()?;
+        let Decoder {
+            src, info, opts, ..
+        } = self;
+        let (header, trees) = info.as_ref().unwrap();
+        do_decode(src, *header, trees, opts, &mut output, &mut Callback(f))?;
+
+        Ok(output)
+    }
+
+    /// Decode the file along with the exact tokens and trees that it was written with.
+    ///
+    /// Use [`Encoder::reencode_to_vec`](crate::Encoder::reencode_to_vec) to encode an edited
+    /// version of the data with the same tokens and trees.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder, format::Token};
+    /// # let compressed = Encoder::for_bytes(b"ABBACABBACD").encode_to_vec().unwrap();
+    /// let decoded = Decoder::for_bytes(&compressed).decode_tokens().unwrap();
+    /// assert_eq!(decoded.data, b"ABBACABBACD");
+    /// assert_eq!(decoded.tokens[0], Token::Literal(b'A'));
+    /// ```
+    pub fn decode_tokens(&mut self) -> Result<DecodedTokens, VpkError> {
+        let header = self.header()?;
+        let trees = self.huffman_trees()?;
+        let mut tokens = Vec::new();
+        let data = self.decode_with_events(|event| tokens.extend(event.token(header.method)))?;
+
+        Ok(DecodedTokens {
+            header,
+            trees,
+            tokens,
+            data,
+        })
+    }
+
+    /// Decode into the caller provided `buf` without allocating,
+    /// and return the number of decompressed bytes.
+    ///
+    /// `buf` must be at least [`VpkHeader::size`] bytes long,
+    /// otherwise [`VpkError::BufferTooSmall`] is returned.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder};
+    /// # let compressed = Encoder::for_bytes(b"ABBACABBACD").encode_to_vec().unwrap();
+    /// let mut buf = [0u8; 64];
+    /// let size = Decoder::for_bytes(&compressed).decode_into(&mut buf).unwrap();
+    /// assert_eq!(&buf[..size], b"ABBACABBACD");
+    /// ```
+    pub fn decode_into(&mut self, buf: &mut [u8]) -> Result<usize, VpkError> {
+        let size = self.header()?.size as usize;
+        self.opts.check_size(size)?;
+        if buf.len() < size {
+            return Err(VpkError::BufferTooSmall(size, buf.len()));
+        }
+
+        let mut output = SliceOutput { buf, pos: 0 };
+        self.decode_to(&mut output)?;
+
+        Ok(size)
+    }
+
+    /// Get the number of bits of `vpk0` data that have been read, including the header and trees.
+    ///
+    /// After decoding, this is the exact length of the `vpk0` stream in bits,
+    /// not counting the padding bits after the last token.
+    #[inline]
+    pub fn input_bits(&self) -> u64 {
+        self.src.position()
+    }
+
+    /// Get the number of bytes of `vpk0` data that have been read.
+    ///
+    /// After decoding, this is the size of the compressed data, including
+    /// the padding bits needed to byte align the last token.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder};
+    /// # let compressed = Encoder::for_bytes(b"ABBACABBACD").encode_to_vec().unwrap();
+    /// let mut data = compressed.clone();
+    /// data.extend_from_slice(b"more data");
+    ///
+    /// let mut decoder = Decoder::for_bytes(&data);
+    /// decoder.decode().unwrap();
+    /// assert_eq!(decoder.input_bytes(), compressed.len() as u64);
+    /// ```
+    #[inline]
+    pub fn input_bytes(&self) -> u64 {
+        (self.src.position() + 7) / 8
+    }
+
+    /// Get back the underlying reader.
+    ///
+    /// The reader is never advanced past the bytes that were needed, so after decoding
+    /// it is positioned right after the end of the `vpk0` stream. This can be used to
+    /// decode `vpk0` data that is followed by other data.
+    /// Note that [`strict`](Decoder::strict) mode reads ahead to check for trailing data.
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.src.into_inner()
+    }
+
+    /// Turn this `Decoder` into a [`VpkReader`] that decodes the data incrementally.
+    ///
+    /// The reader returns errors for the [`max_output_size`](Decoder::max_output_size) and
+    /// [`max_ratio`](Decoder::max_ratio) limits. The other settings and the log are not used.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder};
+    /// use std::io::Read;
+    /// # let compressed = Encoder::for_bytes(b"ABBACABBACD").encode_to_vec().unwrap();
+    /// let mut decoder = Decoder::for_bytes(&compressed);
+    /// decoder.max_output_size(1 << 20).max_ratio(64);
+    /// let mut reader = decoder.into_reader().unwrap();
+    /// let mut decompressed = Vec::new();
+    /// reader.read_to_end(&mut decompressed).unwrap();
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn into_reader(mut self) -> Result<VpkReader<R>, VpkError> {
+        let size = self.header()?.size as usize;
+        self.opts.check_size(size)?;
+        let (header, trees) = self.info.take().unwrap();
+
+        Ok(VpkReader::from_parts(self.src, header, trees, self.opts))
+    }
+
+    /// Check the header against the limits, and create a `Vec` for the decoded output
+    fn output_vec(&mut self) -> Result<Vec<u8>, VpkError> {
+        let size = self.header()?.size as usize;
+        self.opts.check_size(size)?;
+
+        if self.opts.lazy {
+            Ok(Vec::new())
+        } else {
+            Ok(Vec::with_capacity(size))
+        }
+    }
+
+    fn decode_to<O: Output>(&mut self, output: &mut O) -> Result<(), VpkError> {
+        self.get_file_info()?;
+        let Decoder {
+            src,
+            log,
+            info,
+            opts,
+        } = self;
+        let (header, trees) = info.as_ref().unwrap();
+
+        match log {
+            Some(wtr) => do_decode(src, *header, trees, opts, output, &mut TextLog::new(wtr)),
+            None => do_decode(src, *header, trees, opts, output, &mut NoEvents),
+        }
     }
 
     fn get_file_info(&mut self) -> Result<&(VpkHeader, RawTrees), VpkError> {
@@ -113,15 +448,15 @@ impl<'a, R: Read> Decoder<'a, R> {
             Ok(info)
         } else {
             let hdr = VpkHeader::from_bitreader(&mut self.src)?;
-            let offsets = VpkTree::from_bitreader(&mut self.src)?;
-            let lengths = VpkTree::from_bitreader(&mut self.src)?;
+            let trees = read_trees(&mut self.src)?;
 
-            self.info = Some((hdr, [offsets, lengths]));
+            self.info = Some((hdr, trees));
             Ok(self.info.as_ref().unwrap())
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl<'a> Decoder<'a, Cursor<&'a [u8]>> {
     #[inline]
     pub fn for_bytes(bytes: &'a [u8]) -> Self {
@@ -130,6 +465,15 @@ impl<'a> Decoder<'a, Cursor<&'a [u8]>> {
     }
 }
 
+#[cfg(not(feature = "std"))]
+impl<'a> Decoder<'a, &'a [u8]> {
+    #[inline]
+    pub fn for_bytes(bytes: &'a [u8]) -> Self {
+        Self::for_reader(bytes)
+    }
+}
+
+#[cfg(feature = "std")]
 impl<'a> Decoder<'a, BufReader<File>> {
     #[inline]
     pub fn for_file<P: AsRef<Path>>(p: P) -> Result<Self, VpkError> {
@@ -153,7 +497,7 @@ pub fn decode<R: Read>(rdr: R) -> Result<Vec<u8>, VpkError> {
 /// This is a convenience function to decode data without
 /// having to import and set up a [`Decoder`] or a `std::io::Cursor`
 pub fn decode_bytes(bytes: &[u8]) -> Result<Vec<u8>, VpkError> {
-    Decoder::for_reader(Cursor::new(bytes)).decode()
+    Decoder::for_bytes(bytes).decode()
 }
 
 /// Extract the [`VpkHeader`] and [`TreeInfo`] from `vpk0` data
@@ -168,115 +512,262 @@ pub fn vpk_info<R: Read>(rdr: R) -> Result<(VpkHeader, TreeInfo), VpkError> {
         .and_then(|hdr| decoder.trees().map(|t| (hdr, t)))
 }
 
-fn do_decode<R: Read>(opt: &mut Decoder<R>) -> Result<Vec<u8>, VpkError> {
-    let info = if let Some(info) = opt.info.as_ref() {
-        info
-    } else {
-        opt.get_file_info()?;
-        opt.info.as_ref().unwrap()
-    };
-    let &(header, [ref offsets, ref lengths]) = info;
-    let Decoder { src, log, .. } = opt;
+fn do_decode<R: Read, O: Output, E: EventSink>(
+    src: &mut BitReader<R>,
+    header: VpkHeader,
+    trees: &RawTrees,
+    opts: &Options,
+    output: &mut O,
+    events: &mut E,
+) -> Result<(), VpkError> {
+    let [offsets, lengths] = trees;
+    if opts.strict {
+        offsets
+            .check_strict()
+            .map_err(|e| e.in_tree(TreeKind::Offsets))?;
+        lengths
+            .check_strict()
+            .map_err(|e| e.in_tree(TreeKind::Lengths))?;
+    }
+
+    events.event(DecodeEvent::Header(header))?;
+    events.trees(trees)?;
 
-    // set up the log with a map to store the bitsizes of the offsets and lengths
-    let mut log = log.as_mut().map(|l| (l, LogFreq::new()));
+    let output_size = header.size as usize;
+    let mut token_index = 0;
+
+    while output.position() < output_size {
+        let output_position = output.position();
+        decode_token(src, header, trees, opts, output, events).map_err(|e| {
+            e.at(ErrorLocation {
+                bit_offset: src.position(),
+                output_position,
+                token_index,
+            })
+        })?;
+        token_index += 1;
+    }
 
-    if let Some((wtr, _)) = &mut log {
-        writeln!(wtr, "# Header\n{:?}", &header)?;
-        writeln!(wtr, "## Offset / Moveback Tree\n{}", offsets)?;
-        writeln!(wtr, "###> {:?}", offsets)?;
-        writeln!(wtr, "## Length / Size Tree\n{}", lengths)?;
-        writeln!(wtr, "###> {:?}", lengths)?;
-        writeln!(wtr)?;
+    if opts.strict {
+        check_stream_end(src).map_err(|e| {
+            e.at(ErrorLocation {
+                bit_offset: src.position(),
+                output_position: output.position(),
+                token_index,
+            })
+        })?;
     }
 
+    Ok(())
+}
+
+/// Decode a single uncoded byte or copy-back into `output`
+fn decode_token<R: Read, O: Output, E: EventSink>(
+    src: &mut BitReader<R>,
+    header: VpkHeader,
+    [offsets, lengths]: &RawTrees,
+    opts: &Options,
+    output: &mut O,
+    events: &mut E,
+) -> Result<(), VpkError> {
     let output_size = header.size as usize;
-    let mut output: Vec<u8> = Vec::with_capacity(output_size);
-
-    while output.len() < output_size {
-        if src.read_bit()? {
-            let initial_move = offsets.read_value(src)? as usize;
-            let move_back = match header.method {
-                VpkMethod::TwoSample => {
-                    if initial_move < 3 {
-                        let l = initial_move + 1;
-                        let u = offsets.read_value(src)? as usize;
-
-                        if let Some((wtr, _)) = &mut log {
-                            writeln!(
-                                wtr,
-                                "Encoded 2-sample => initial move: {} | second move: {}",
-                                initial_move, u
-                            )?;
-                        }
-
-                        (l + (u << 2)) - 8
-                    } else {
-                        if let Some((wtr, _)) = &mut log {
-                            writeln!(wtr, "Encoded 2-sample => initial move: {}", initial_move)?;
-                        }
-                        (initial_move << 2) - 8
-                    }
-                }
-                VpkMethod::OneSample => initial_move,
-            };
-
-            // get start position in output, and the number of bytes to copy-back
-            if move_back > output.len() {
-                return Err(VpkError::BadLookBack(move_back, output.len()));
-            }
+    let position = output.position();
+    let bit_offset = src.position();
 
-            let start = output.len() - move_back;
-            let size = lengths.read_value(src)? as usize;
-
-            if let Some((wtr, map)) = &mut log {
-                let size_bits = usize::MAX.count_ones() - size.leading_zeros();
-                let mb_bits = usize::MAX.count_ones() - move_back.leading_zeros();
-                writeln!(
-                    wtr,
-                    "{:04x} - Encoded [Copyback]: size: {} ({} bits) mb: {} ({} bits) | start: {:04x}",
-                    output.len(),
-                    size,
-                    size_bits,
-                    move_back,
-                    mb_bits,
-                    start
-                )?;
-                *map.size.entry(size_bits as u8).or_insert(0) += 1;
-                *map.moveback.entry(mb_bits as u8).or_insert(0) += 1;
-            }
+    if src.read_bit()? {
+        let sample = read_offset_sample(src, header.method, offsets)?;
+        let move_back = sample.move_back(header.method);
 
-            for i in start..start + size {
-                let byte = output[i];
-                output.push(byte);
-            }
-            if let Some((wtr, _)) = &mut log {
-                writeln!(wtr, "\t{:02x?}", &output[start..start + size])?;
-            }
-        } else {
-            let byte = src.read(8)?;
-            output.push(byte);
+        // check the start position in output before reading the number of bytes to copy-back
+        if move_back > position {
+            return Err(VpkError::BadLookBack(move_back, position));
+        }
+
+        let length = lengths.read_value(src)? as usize;
+        let end = position.saturating_add(length);
+        if opts.strict && end > output_size {
+            return Err(VpkError::CopyOvershoot(end, output_size));
+        }
+        opts.check(end, src.position())?;
+        output.copy_back(move_back, length)?;
+
+        let decoded = output.decoded();
+        let end = decoded.len().min(position + length);
+        events.event(DecodeEvent::CopyBack {
+            position,
+            bit_offset,
+            length,
+            move_back,
+            sample,
+            data: &decoded[position..end],
+        })
+    } else {
+        let byte = src.read(8)? as u8;
+        opts.check(position + 1, src.position())?;
+        output.push(byte);
+
+        events.event(DecodeEvent::Literal {
+            position,
+            bit_offset,
+            byte,
+        })
+    }
+}
+
+/// Check that there are only zero padding bits and no trailing data after the last token
+fn check_stream_end<R: Read>(src: &mut BitReader<R>) -> Result<(), VpkError> {
+    let padding = src.byte_align();
+    if padding != 0 {
+        return Err(VpkError::NonZeroPadding(padding));
+    }
+
+    match src.fill_byte() {
+        Ok(()) => Err(VpkError::TrailingData),
+        Err(VpkError::UnexpectedEof) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the offsets and lengths trees that follow the `vpk0` header
+fn read_trees<R: Read>(src: &mut BitReader<R>) -> Result<RawTrees, VpkError> {
+    let offsets = VpkTree::from_bitreader(src).map_err(|e| e.in_tree(TreeKind::Offsets))?;
+    let lengths = VpkTree::from_bitreader(src).map_err(|e| e.in_tree(TreeKind::Lengths))?;
+
+    Ok([offsets, lengths])
+}
+
+/// Resource limits and validation settings for decoding untrusted data
+#[derive(Debug, Clone, Copy, Default)]
+struct Options {
+    max_size: Option<usize>,
+    /// maximum number of output bytes per input byte
+    max_ratio: Option<u32>,
+    /// don't allocate the full output size before decoding
+    lazy: bool,
+    /// reject streams that other decoders might not handle
+    strict: bool,
+}
+
+impl Options {
+    fn check_size(&self, size: usize) -> Result<(), VpkError> {
+        match self.max_size {
+            Some(max) if size > max => Err(VpkError::OutputTooLarge(size, max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that `size` bytes of output from `input_bits` of input are within the limits
+    fn check(&self, size: usize, input_bits: u64) -> Result<(), VpkError> {
+        self.check_size(size)?;
 
-            if let Some((wtr, _)) = &mut log {
-                writeln!(wtr, "{:04x} - Uncoded: {:02x}", output.len() - 1, byte)?;
+        if let Some(ratio) = self.max_ratio {
+            let input = (input_bits + 7) / 8;
+            if size as u64 > input.saturating_mul(ratio as u64) {
+                return Err(VpkError::RatioExceeded(size, input));
             }
         }
+
+        Ok(())
     }
+}
 
-    Ok(output)
+/// Storage for decoded data, so that the same decoding loop can
+/// fill a growable `Vec` or a fixed size slice
+trait Output {
+    /// Number of bytes decoded so far
+    fn position(&self) -> usize;
+    /// Add an uncoded byte
+    fn push(&mut self, byte: u8);
+    /// Copy `size` bytes from `move_back` bytes before the current position
+    fn copy_back(&mut self, move_back: usize, size: usize) -> Result<(), VpkError>;
+    /// All of the stored decoded data
+    fn decoded(&self) -> &[u8];
 }
 
-#[derive(Debug)]
-struct LogFreq {
-    size: BTreeMap<u8, u32>,
-    moveback: BTreeMap<u8, u32>,
+impl Output for Vec<u8> {
+    fn position(&self) -> usize {
+        self.len()
+    }
+
+    fn push(&mut self, byte: u8) {
+        Vec::push(self, byte)
+    }
+
+    fn copy_back(&mut self, move_back: usize, size: usize) -> Result<(), VpkError> {
+        let pos = self.len();
+        check_lookback(move_back, size, pos)?;
+        self.resize(pos + size, 0);
+        copy_back(self, pos, move_back);
+
+        Ok(())
+    }
+
+    fn decoded(&self) -> &[u8] {
+        self
+    }
 }
 
-impl LogFreq {
-    fn new() -> Self {
-        Self {
-            size: BTreeMap::new(),
-            moveback: BTreeMap::new(),
+/// A caller provided buffer of at least `header.size` bytes
+struct SliceOutput<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl Output for SliceOutput<'_> {
+    fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.buf[self.pos] = byte;
+        self.pos += 1;
+    }
+
+    fn copy_back(&mut self, move_back: usize, size: usize) -> Result<(), VpkError> {
+        check_lookback(move_back, size, self.pos)?;
+        // a copy-back can go past the decompressed size; drop anything past the buffer
+        let end = self.buf.len().min(self.pos + size);
+        copy_back(&mut self.buf[..end], self.pos, move_back);
+        self.pos += size;
+
+        Ok(())
+    }
+
+    fn decoded(&self) -> &[u8] {
+        &self.buf[..self.pos.min(self.buf.len())]
+    }
+}
+
+/// Check that moving back `move_back` bytes from `pos` to copy `size` bytes is valid
+fn check_lookback(move_back: usize, size: usize, pos: usize) -> Result<(), VpkError> {
+    if move_back > pos || (move_back == 0 && size > 0) {
+        Err(VpkError::BadLookBack(move_back, pos))
+    } else {
+        Ok(())
+    }
+}
+
+/// Fill `buf[pos..]` by copying the bytes starting `move_back` bytes before `pos`.
+/// The source and destination can overlap to repeat a short pattern.
+fn copy_back(buf: &mut [u8], pos: usize, move_back: usize) {
+    for i in pos..buf.len() {
+        buf[i] = buf[i - move_back];
+    }
+}
+
+/// Read the offset sample(s) for an encoded copy-back based on the `vpk0` `method`
+fn read_offset_sample<R: Read>(
+    src: &mut BitReader<R>,
+    method: VpkMethod,
+    offsets: &VpkTree,
+) -> Result<OffsetSample, VpkError> {
+    let initial_move = offsets.read_value(src)? as usize;
+
+    match method {
+        VpkMethod::TwoSample if initial_move < 3 => {
+            let second_move = offsets.read_value(src)? as usize;
+            Ok(OffsetSample::Two(initial_move, second_move))
         }
+        _ => Ok(OffsetSample::One(initial_move)),
     }
 }
@@ -0,0 +1,231 @@
+use super::{DecodeEvent, Decoder, TreeInfo};
+use crate::errors::VpkError;
+use crate::format::{Token, VpkHeader};
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(not(feature = "std"))]
+use crate::io::Read;
+#[cfg(feature = "std")]
+use std::io::Read;
+
+/// Number of bits in the `vpk0` header
+const HEADER_BITS: u64 = 9 * 8;
+
+/// How two `vpk0` files differ, from [`diff`]
+///
+/// The `(left, right)` pairs are only set when the two sides are different.
+/// The `Display` output is a short report of every difference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VpkDiff {
+    pub header: Option<(VpkHeader, VpkHeader)>,
+    pub trees: Option<(TreeInfo, TreeInfo)>,
+    /// The first token where the two parses diverge
+    pub first_difference: Option<TokenDifference>,
+    /// The bits used by each part of the `(left, right)` files
+    pub costs: (BitCosts, BitCosts),
+}
+
+/// The first token that is different in two `vpk0` files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenDifference {
+    /// Index of the token in both files
+    pub index: usize,
+    /// Position of the token in the decompressed output, which is the same in both files
+    pub position: usize,
+    /// The token in the left file, or `None` if the left file has fewer tokens
+    pub left: Option<DiffToken>,
+    /// The token in the right file, or `None` if the right file has fewer tokens
+    pub right: Option<DiffToken>,
+}
+
+/// A token, and where it is stored in a `vpk0` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffToken {
+    pub token: Token,
+    /// Position of the token in the compressed input, in bits
+    pub bit_offset: u64,
+    /// Number of bits used by the token
+    pub bits: u64,
+}
+
+/// The number of bits used by each part of a `vpk0` file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BitCosts {
+    /// Bits used by the offsets and lengths trees
+    pub trees: u64,
+    /// Bits used by the uncoded bytes, including the flag bits
+    pub literals: u64,
+    /// Bits used by the copy-backs, including the flag bits
+    pub matches: u64,
+    /// Every bit in the file, including the header, but not the padding after the last token
+    pub total: u64,
+}
+
+impl VpkDiff {
+    /// Check if the two files have the same header, trees, and tokens
+    pub fn is_identical(&self) -> bool {
+        self.header.is_none() && self.trees.is_none() && self.first_difference.is_none()
+    }
+
+    /// The number of bits the right file uses more than the left file
+    pub fn bit_difference(&self) -> i64 {
+        self.costs.1.total as i64 - self.costs.0.total as i64
+    }
+}
+
+impl fmt::Display for VpkDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((left, right)) = &self.header {
+            writeln!(f, "header: {:?} | {:?}", left, right)?;
+        }
+        if let Some((left, right)) = &self.trees {
+            if left.offsets != right.offsets {
+                writeln!(f, "offsets tree: {} | {}", left.offsets, right.offsets)?;
+            }
+            if left.lengths != right.lengths {
+                writeln!(f, "lengths tree: {} | {}", left.lengths, right.lengths)?;
+            }
+        }
+        if let Some(diff) = &self.first_difference {
+            write!(f, "token {} at {:04x}: ", diff.index, diff.position)?;
+            write_side(f, diff.left)?;
+            write!(f, " | ")?;
+            write_side(f, diff.right)?;
+            writeln!(f)?;
+        }
+
+        let (left, right) = &self.costs;
+        writeln!(
+            f,
+            "bits: trees {} | {}, literals {} | {}, matches {} | {}, total {} | {} ({:+})",
+            left.trees,
+            right.trees,
+            left.literals,
+            right.literals,
+            left.matches,
+            right.matches,
+            left.total,
+            right.total,
+            self.bit_difference()
+        )
+    }
+}
+
+fn write_side(f: &mut fmt::Formatter, side: Option<DiffToken>) -> fmt::Result {
+    match side {
+        Some(t) => write!(f, "{:?} at bit {} ({} bits)", t.token, t.bit_offset, t.bits),
+        None => write!(f, "end of file"),
+    }
+}
+
+/// Decode the `vpk0` files in `left` and `right`, and find how they differ
+/// ```
+/// # use vpk0::{Encoder, LazyMatching};
+/// let data = b"sam I am I am sam, sam I am";
+/// let nintendo = Encoder::for_bytes(data).encode_to_vec().unwrap();
+/// let greedy = Encoder::for_bytes(data)
+///     .lazy_matching(LazyMatching::Greedy)
+///     .encode_to_vec()
+///     .unwrap();
+///
+/// let diff = vpk0::diff(&nintendo[..], &greedy[..]).unwrap();
+/// if let Some(first) = diff.first_difference {
+///     println!("the parses diverge at byte {}", first.position);
+/// }
+/// println!("{}", diff);
+/// ```
+pub fn diff<L: Read, R: Read>(left: L, right: R) -> Result<VpkDiff, VpkError> {
+    let left = ParsedFile::new(left)?;
+    let right = ParsedFile::new(right)?;
+
+    let index = left
+        .tokens
+        .iter()
+        .zip(&right.tokens)
+        .take_while(|(l, r)| l.token == r.token)
+        .count();
+    let first_difference = if index == left.tokens.len() && index == right.tokens.len() {
+        None
+    } else {
+        Some(TokenDifference {
+            index,
+            position: left.tokens[..index].iter().map(|t| t.token.len()).sum(),
+            left: left.tokens.get(index).copied(),
+            right: right.tokens.get(index).copied(),
+        })
+    };
+
+    Ok(VpkDiff {
+        header: pair_if_different(left.header, right.header),
+        trees: pair_if_different(left.trees, right.trees),
+        first_difference,
+        costs: (left.costs, right.costs),
+    })
+}
+
+fn pair_if_different<T: PartialEq>(left: T, right: T) -> Option<(T, T)> {
+    if left == right {
+        None
+    } else {
+        Some((left, right))
+    }
+}
+
+/// The parts of a decoded `vpk0` file that are compared
+struct ParsedFile {
+    header: VpkHeader,
+    trees: TreeInfo,
+    tokens: Vec<DiffToken>,
+    costs: BitCosts,
+}
+
+impl ParsedFile {
+    fn new<R: Read>(rdr: R) -> Result<Self, VpkError> {
+        let mut decoder = Decoder::for_reader(rdr);
+        let header = decoder.header()?;
+        let trees = decoder.trees()?;
+        let trees_end = decoder.input_bits();
+
+        let mut tokens = Vec::new();
+        decoder.decode_with_events(|event| {
+            let bit_offset = match event {
+                DecodeEvent::Literal { bit_offset, .. }
+                | DecodeEvent::CopyBack { bit_offset, .. } => bit_offset,
+                _ => return,
+            };
+            if let Some(token) = event.token(header.method) {
+                tokens.push(DiffToken {
+                    token,
+                    bit_offset,
+                    bits: 0,
+                });
+            }
+        })?;
+
+        // each token ends where the next one starts
+        let total = decoder.input_bits();
+        let mut costs = BitCosts {
+            trees: trees_end - HEADER_BITS,
+            total,
+            ..BitCosts::default()
+        };
+        let mut end = total;
+        for t in tokens.iter_mut().rev() {
+            t.bits = end - t.bit_offset;
+            end = t.bit_offset;
+
+            match t.token {
+                Token::Literal(..) => costs.literals += t.bits,
+                _ => costs.matches += t.bits,
+            }
+        }
+
+        Ok(Self {
+            header,
+            trees,
+            tokens,
+            costs,
+        })
+    }
+}
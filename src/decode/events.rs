@@ -0,0 +1,175 @@
+use super::{LogWtr, RawTrees, TreeInfo};
+use crate::errors::VpkError;
+use crate::format::{OffsetSample, Token, VpkHeader, VpkMethod};
+
+/// A typed step in decoding a `vpk0` file
+///
+/// Pass a callback to [`Decoder::decode_with_events`](crate::Decoder::decode_with_events)
+/// to get these events while decoding. The header and trees are always reported first,
+/// and then one event for each uncoded byte or encoded copy-back in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DecodeEvent<'d> {
+    /// The `vpk0` header
+    Header(VpkHeader),
+    /// The offset and length Huffman trees
+    Trees(TreeInfo),
+    /// An uncoded byte
+    Literal {
+        /// position of the byte in the decompressed output
+        position: usize,
+        /// position of the token in the compressed input, in bits
+        bit_offset: u64,
+        byte: u8,
+    },
+    /// An encoded LZSS copy-back
+    CopyBack {
+        /// position in the decompressed output where the copy starts
+        position: usize,
+        /// position of the token in the compressed input, in bits
+        bit_offset: u64,
+        /// number of bytes copied
+        length: usize,
+        /// number of bytes to move back in the output before copying
+        move_back: usize,
+        /// the raw offset value(s) stored in the file
+        sample: OffsetSample,
+        /// the bytes that were copied into the output
+        data: &'d [u8],
+    },
+}
+
+impl DecodeEvent<'_> {
+    /// The token for a literal or copy-back in a file encoded with `method`
+    pub(super) fn token(&self, method: VpkMethod) -> Option<Token> {
+        match *self {
+            Self::Literal { byte, .. } => Some(Token::Literal(byte)),
+            Self::CopyBack { length, sample, .. } => Some(match (method, sample) {
+                (VpkMethod::OneSample, OffsetSample::One(offset)) => {
+                    Token::Match { offset, length }
+                }
+                (_, offset) => Token::TwoSampleMatch { offset, length },
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Something that handles events from the decoding loop
+pub(super) trait EventSink {
+    /// `false` if the events are ignored, so that they do not need to be created
+    const ACTIVE: bool = true;
+
+    fn event(&mut self, event: DecodeEvent<'_>) -> Result<(), VpkError>;
+
+    /// Report the trees that were read from the file
+    fn trees(&mut self, trees: &RawTrees) -> Result<(), VpkError> {
+        if Self::ACTIVE {
+            self.event(DecodeEvent::Trees(trees.into()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Ignore all events
+pub(super) struct NoEvents;
+
+impl EventSink for NoEvents {
+    const ACTIVE: bool = false;
+
+    #[inline(always)]
+    fn event(&mut self, _event: DecodeEvent<'_>) -> Result<(), VpkError> {
+        Ok(())
+    }
+}
+
+/// Pass events to a user callback
+pub(super) struct Callback<F>(pub F);
+
+impl<F: FnMut(DecodeEvent<'_>)> EventSink for Callback<F> {
+    fn event(&mut self, event: DecodeEvent<'_>) -> Result<(), VpkError> {
+        (self.0)(event);
+        Ok(())
+    }
+}
+
+/// Write events as human readable text for [`Decoder::with_logging`](crate::Decoder::with_logging)
+pub(super) struct TextLog<'w, 'a> {
+    wtr: &'w mut LogWtr<'a>,
+    method: VpkMethod,
+}
+
+impl<'w, 'a> TextLog<'w, 'a> {
+    pub(super) fn new(wtr: &'w mut LogWtr<'a>) -> Self {
+        Self {
+            wtr,
+            method: VpkMethod::OneSample,
+        }
+    }
+}
+
+impl EventSink for TextLog<'_, '_> {
+    fn trees(&mut self, [offsets, lengths]: &RawTrees) -> Result<(), VpkError> {
+        let wtr = &mut self.wtr;
+        writeln!(wtr, "## Offset / Moveback Tree\n{}", offsets)?;
+        writeln!(wtr, "###> {:?}", offsets)?;
+        writeln!(wtr, "## Length / Size Tree\n{}", lengths)?;
+        writeln!(wtr, "###> {:?}", lengths)?;
+        writeln!(wtr)?;
+
+        Ok(())
+    }
+
+    fn event(&mut self, event: DecodeEvent<'_>) -> Result<(), VpkError> {
+        let wtr = &mut self.wtr;
+
+        match event {
+            DecodeEvent::Header(header) => {
+                self.method = header.method;
+                writeln!(wtr, "# Header\n{:?}", &header)?;
+            }
+            // the raw trees are written by `trees`
+            DecodeEvent::Trees(..) => (),
+            DecodeEvent::Literal { position, byte, .. } => {
+                writeln!(wtr, "{:04x} - Uncoded: {:02x}", position, byte)?;
+            }
+            DecodeEvent::CopyBack {
+                position,
+                length,
+                move_back,
+                sample,
+                data,
+                ..
+            } => {
+                match sample {
+                    OffsetSample::Two(initial_move, u) => writeln!(
+                        wtr,
+                        "Encoded 2-sample => initial move: {} | second move: {}",
+                        initial_move, u
+                    )?,
+                    OffsetSample::One(initial_move) if self.method == VpkMethod::TwoSample => {
+                        writeln!(wtr, "Encoded 2-sample => initial move: {}", initial_move)?
+                    }
+                    OffsetSample::One(..) => (),
+                }
+
+                let size_bits = usize::MAX.count_ones() - length.leading_zeros();
+                let mb_bits = usize::MAX.count_ones() - move_back.leading_zeros();
+                writeln!(
+                    wtr,
+                    "{:04x} - Encoded [Copyback]: size: {} ({} bits) mb: {} ({} bits) | start: {:04x}",
+                    position,
+                    length,
+                    size_bits,
+                    move_back,
+                    mb_bits,
+                    position - move_back
+                )?;
+                writeln!(wtr, "\t{:02x?}", data)?;
+            }
+        }
+
+        Ok(())
+    }
+}
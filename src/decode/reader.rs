@@ -0,0 +1,201 @@
+use super::{check_lookback, read_offset_sample, Decoder, Options, RawTrees, TreeInfo};
+use crate::bits::BitReader;
+use crate::errors::{ErrorLocation, VpkError};
+use crate::format::{VpkHeader, VpkMethod, VpkTree};
+use std::io::{self, Read};
+
+/// Decompress `vpk0` data incrementally through the [`Read`] trait
+///
+/// Unlike [`Decoder`](crate::Decoder), a `VpkReader` never holds the entire decompressed
+/// output. It only keeps a sliding window of the most recently decoded bytes,
+/// which grows up to the largest move back that the file's offset tree can encode.
+///
+/// The header and Huffman trees are read when the `VpkReader` is created.
+/// Use [`Decoder::into_reader`](crate::Decoder::into_reader) to limit the decoded size
+/// of untrusted data.
+/// ```
+/// # use vpk0::{Encoder, VpkReader};
+/// use std::io::Read;
+///
+/// let original = b"ABBACABBACDABBACABBACD";
+/// let compressed = Encoder::for_bytes(original).encode_to_vec().unwrap();
+///
+/// let mut reader = VpkReader::new(&compressed[..]).unwrap();
+/// let mut decompressed = Vec::new();
+/// reader.read_to_end(&mut decompressed).unwrap();
+/// assert_eq!(&original[..], decompressed);
+/// ```
+pub struct VpkReader<R: Read> {
+    src: BitReader<R>,
+    header: VpkHeader,
+    trees: RawTrees,
+    opts: Options,
+    /// ring buffer of the most recently decoded bytes, which grows up to `window_size`
+    window: Vec<u8>,
+    window_size: usize,
+    /// total number of bytes decoded
+    pos: usize,
+    /// an in-progress copy-back of (move back, bytes remaining)
+    copy: Option<(usize, usize)>,
+    /// number of tokens decoded
+    tokens: usize,
+    /// an error that happened after some bytes were already read out
+    err: Option<VpkError>,
+}
+
+impl<R: Read> VpkReader<R> {
+    /// Create a new `VpkReader` by reading the `vpk0` header and trees from `rdr`
+    pub fn new(rdr: R) -> Result<Self, VpkError> {
+        Decoder::for_reader(rdr).into_reader()
+    }
+
+    pub(super) fn from_parts(
+        src: BitReader<R>,
+        header: VpkHeader,
+        trees: RawTrees,
+        opts: Options,
+    ) -> Self {
+        let window_size = max_move_back(&trees[0], header.method)
+            .min(header.size as usize)
+            .max(1);
+
+        Self {
+            src,
+            header,
+            trees,
+            opts,
+            window: Vec::new(),
+            window_size,
+            pos: 0,
+            copy: None,
+            tokens: 0,
+            err: None,
+        }
+    }
+
+    /// Get the [`VpkHeader`] of the `vpk0` data being read
+    #[inline]
+    pub fn header(&self) -> VpkHeader {
+        self.header
+    }
+
+    /// Get the [`TreeInfo`] of the `vpk0` data being read
+    #[inline]
+    pub fn trees(&self) -> TreeInfo {
+        (&self.trees).into()
+    }
+
+    /// Decode the next byte, or `None` if the all of the data has been decoded
+    fn next_byte(&mut self) -> Result<Option<u8>, VpkError> {
+        loop {
+            if let Some((move_back, remaining)) = self.copy {
+                if remaining > 0 {
+                    let byte = self.window[(self.pos - move_back) % self.window_size];
+                    self.push(byte);
+                    self.copy = Some((move_back, remaining - 1));
+
+                    return Ok(Some(byte));
+                }
+                self.copy = None;
+            }
+
+            if self.pos >= self.header.size as usize {
+                return Ok(None);
+            }
+
+            let output_position = self.pos;
+            let token = self.next_token().map_err(|e| {
+                e.at(ErrorLocation {
+                    bit_offset: self.src.position(),
+                    output_position,
+                    token_index: self.tokens,
+                })
+            })?;
+            self.tokens += 1;
+
+            if let Some(byte) = token {
+                return Ok(Some(byte));
+            }
+        }
+    }
+
+    /// Read the next token, and return the byte if it was uncoded,
+    /// or start a new copy-back
+    fn next_token(&mut self) -> Result<Option<u8>, VpkError> {
+        let [offsets, lengths] = &self.trees;
+        if self.src.read_bit()? {
+            let move_back = read_offset_sample(&mut self.src, self.header.method, offsets)?
+                .move_back(self.header.method);
+            if move_back > self.pos.min(self.window_size) {
+                return Err(VpkError::BadLookBack(move_back, self.pos));
+            }
+
+            let size = lengths.read_value(&mut self.src)? as usize;
+            check_lookback(move_back, size, self.pos)?;
+            self.opts
+                .check(self.pos.saturating_add(size), self.src.position())?;
+
+            self.copy = Some((move_back, size));
+            Ok(None)
+        } else {
+            let byte = self.src.read(8)? as u8;
+            self.opts.check(self.pos + 1, self.src.position())?;
+            self.push(byte);
+
+            Ok(Some(byte))
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        // the window is only filled up to its full size as data is decoded,
+        // so that an untrusted header can't make it allocate
+        if self.window.len() < self.window_size {
+            self.window.push(byte);
+        } else {
+            self.window[self.pos % self.window_size] = byte;
+        }
+        self.pos += 1;
+    }
+}
+
+impl<R: Read> Read for VpkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(e) = self.err.take() {
+            return Err(e.into());
+        }
+
+        let mut n = 0;
+        for slot in buf.iter_mut() {
+            match self.next_byte() {
+                Ok(Some(byte)) => {
+                    *slot = byte;
+                    n += 1;
+                }
+                Ok(None) => break,
+                // report the error on the next call, so that read bytes aren't lost
+                Err(e) if n > 0 => {
+                    self.err = Some(e);
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// Calculate the largest move back that can be encoded with the `offsets` tree
+fn max_move_back(offsets: &VpkTree, method: VpkMethod) -> usize {
+    let max_sample = offsets.max_leaf().map_or(0, |bits| {
+        1usize
+            .checked_shl(bits as u32)
+            .map_or(usize::MAX, |n| n - 1)
+    });
+
+    match method {
+        VpkMethod::OneSample => max_sample,
+        // (3 + (sample << 2)) - 8 is always less than (sample << 2)
+        VpkMethod::TwoSample => max_sample.saturating_mul(4),
+    }
+}
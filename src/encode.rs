@@ -1,21 +1,26 @@
 use crate::{
-    errors::VpkError,
-    format::{VpkHeader, VpkMethod},
+    decode::DecodedTokens,
+    errors::{TreeKind, VpkError},
+    format::{HuffmanTree, OffsetSample, Token, VpkHeader, VpkMethod},
 };
 use bitstream_io::{BigEndian, BitWriter};
 use std::{
+    convert::TryInto,
     fs::File,
     io::Write,
     io::{BufReader, BufWriter, Cursor, Read},
     path::Path,
 };
 
-mod huffman;
+pub(crate) mod huffman;
+pub(crate) mod infer;
 pub(crate) mod lzss;
+mod optimal;
+mod reencode;
 
 use self::{
-    huffman::{EncodedMaps, MapTree},
-    lzss::{LzssByte, LzssPass, LzssSettings},
+    huffman::{EncodedMaps, MapTree, UserTree, MAX_CODE_LENGTH},
+    lzss::{LazyMatching, LazyPolicy, LzssByte, LzssPass, LzssSettings},
 };
 
 type BitSize = u8;
@@ -31,6 +36,26 @@ pub enum LzssBackend {
     Kmp,
     /// Nintendo matching search with a modified, slower Knuth–Morris–Pratt algorithm
     KmpAhead,
+    /// Fast search by following a chain of earlier positions that start with the same bytes.
+    /// At most `depth` positions are checked for each match, so a smaller depth is faster,
+    /// but might miss the best match. Matches are chosen like [`Brute`](LzssBackend::Brute),
+    /// so the output is the same if the depth is large enough.
+    HashChain { depth: usize },
+    /// Search a binary tree of the earlier positions in the window.
+    /// This always finds the same matches as [`Brute`](LzssBackend::Brute), but is much faster
+    BinaryTree,
+}
+
+/// The algorithm used to build the offsets and lengths Huffman trees when encoding a `vpk0` file
+///
+/// Trees set with [`Encoder::with_offsets`] or [`Encoder::with_lengths`] are used as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TreeMode {
+    /// A Huffman tree with a leaf for most bit sizes
+    Huffman,
+    /// Group the bit sizes into a few leaves, like Nintendo's encoder.
+    /// Along with [`LzssBackend::Brute`], this is byte-matching for *Super Smash Bros.*
+    Nintendo,
 }
 
 /// Specify the encoding settings, such as window size, logging, input, and output
@@ -57,6 +82,9 @@ pub enum LzssBackend {
 ///   * 8 bit lookahead (256 bytes)
 ///   * Minimum match of 3 bytes
 ///   * [`Brute`] match searching
+///   * [`Nintendo`](LazyMatching::Nintendo) lazy matching with a depth of 10 bytes
+/// * [`Huffman`](TreeMode::Huffman) trees with codes of at most 32 bits
+/// * No optimal parsing
 ///
 /// [`for_reader()`]: Encoder::for_reader
 /// [`for_file()`]: Encoder::for_file
@@ -70,9 +98,38 @@ pub struct Encoder<'a, R> {
     method: VpkMethod,
     settings: LzssSettings,
     backend: LzssBackend,
+    trees: TreeOptions<'a>,
+    lazy: LazyMatching,
+    policy: Option<&'a mut dyn LazyPolicy>,
+    optimal: bool,
     log: Option<LogWtr<'a>>,
-    offsets: Option<&'a str>,
-    lengths: Option<&'a str>,
+    added_leaves: AddedLeaves,
+}
+
+/// The settings for the offsets and lengths Huffman trees
+struct TreeOptions<'a> {
+    offsets: Option<UserTree<'a>>,
+    lengths: Option<UserTree<'a>>,
+    mode: TreeMode,
+    max_code_length: u8,
+    extend: bool,
+}
+
+impl TreeOptions<'_> {
+    fn build(&self, pass: &LzssPass) -> Result<EncodedMaps, VpkError> {
+        let (offsets, lengths) = (self.offsets.as_ref(), self.lengths.as_ref());
+        let (mode, limit) = (self.mode, self.max_code_length);
+        EncodedMaps::new(offsets, lengths, mode, limit, self.extend, pass)
+    }
+}
+
+/// The leaves added to the user-provided trees by [`Encoder::extend_trees`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddedLeaves {
+    /// Bit sizes of the leaves added to the offsets tree
+    pub offsets: Vec<u8>,
+    /// Bit sizes of the leaves added to the lengths tree
+    pub lengths: Vec<u8>,
 }
 
 impl<'a, R: Read> Encoder<'a, R> {
@@ -84,9 +141,18 @@ impl<'a, R: Read> Encoder<'a, R> {
             method: VpkMethod::OneSample,
             settings: LzssSettings::default(),
             backend: LzssBackend::Brute,
+            trees: TreeOptions {
+                offsets: None,
+                lengths: None,
+                mode: TreeMode::Huffman,
+                max_code_length: MAX_CODE_LENGTH,
+                extend: false,
+            },
+            lazy: LazyMatching::default(),
+            policy: None,
+            optimal: false,
             log: None,
-            offsets: None,
-            lengths: None,
+            added_leaves: AddedLeaves::default(),
         }
     }
 
@@ -130,6 +196,78 @@ impl<'a, R: Read> Encoder<'a, R> {
         self
     }
 
+    /// Set how the encoder checks the following bytes for a longer match after finding a match.
+    /// See [`LazyMatching`] for the options.
+    /// ```
+    /// # use vpk0::{Encoder, LazyMatching};
+    /// let compressed = Encoder::for_bytes(b"sam I am I am sam")
+    ///     .lazy_matching(LazyMatching::OneStep)
+    ///     .encode_to_vec();
+    /// ```
+    #[inline]
+    pub fn lazy_matching(&mut self, lazy: LazyMatching) -> &mut Self {
+        self.lazy = lazy;
+        self.policy = None;
+        self
+    }
+
+    /// Use a custom [`LazyPolicy`] to choose which matches to use.
+    #[inline]
+    pub fn lazy_policy<P: LazyPolicy>(&mut self, policy: &'a mut P) -> &mut Self {
+        self.policy = Some(policy as &'a mut dyn LazyPolicy);
+        self
+    }
+
+    /// Choose the literals and matches with a small total size in bits,
+    /// based on the real costs of the offset and length Huffman trees.
+    /// The input is parsed again with each new set of trees until the output stops shrinking.
+    ///
+    /// This gives a smaller output, but is slower and does not match Nintendo's encoder.
+    /// The closest match of each length and the longest match of each offset bit size
+    /// are checked. The trees change with the parse, so this is a heuristic,
+    /// and the output is not guaranteed to be the smallest possible `vpk0` file.
+    /// The [`LzssBackend`] and lazy matching settings are only used for the first parse.
+    /// ```
+    /// # use vpk0::Encoder;
+    /// let compressed = Encoder::for_bytes(b"sam I am I am sam")
+    ///     .optimal_parsing(true)
+    ///     .encode_to_vec();
+    /// ```
+    #[inline]
+    pub fn optimal_parsing(&mut self, optimal: bool) -> &mut Self {
+        self.optimal = optimal;
+        self
+    }
+
+    /// Set the algorithm used to build the Huffman trees that are not set manually.
+    /// ```
+    /// # use vpk0::{Encoder, LzssBackend, TreeMode};
+    /// let compressed = Encoder::for_bytes(b"sam I am I am sam")
+    ///     .lzss_backend(LzssBackend::Brute)
+    ///     .tree_mode(TreeMode::Nintendo)
+    ///     .encode_to_vec();
+    /// ```
+    #[inline]
+    pub fn tree_mode(&mut self, mode: TreeMode) -> &mut Self {
+        self.trees.mode = mode;
+        self
+    }
+
+    /// Set the longest Huffman code, in bits, for the trees that are not set manually.
+    /// A tree with longer codes is rebuilt as the best tree within the limit, which may
+    /// combine bit sizes into one leaf. The limit is at most 32 bits, which is also the default.
+    /// ```
+    /// # use vpk0::Encoder;
+    /// let compressed = Encoder::for_bytes(b"sam I am I am sam")
+    ///     .max_code_length(4)
+    ///     .encode_to_vec();
+    /// ```
+    #[inline]
+    pub fn max_code_length(&mut self, bits: u8) -> &mut Self {
+        self.trees.max_code_length = bits.min(MAX_CODE_LENGTH);
+        self
+    }
+
     /// Manually set the offset Huffman Tree with a text based representation of a tree.
     /// This representation can be extracted from a `vpk0` file by [`vpk_info`](crate::vpk_info)
     /// or [`Decoder::trees`](crate::Decoder::trees).
@@ -140,10 +278,24 @@ impl<'a, R: Read> Encoder<'a, R> {
     ///     .encode_to_vec();
     /// ```
     /// Note that the encoding will fail if there is an offset whose size in bits is larger
-    /// than the largest provided offset.
+    /// than the largest provided offset, unless [`extend_trees`](Encoder::extend_trees) is set.
     #[inline]
     pub fn with_offsets(&mut self, o: &'a str) -> &mut Self {
-        self.offsets = Some(o);
+        self.trees.offsets = Some(UserTree::Text(o));
+        self
+    }
+
+    /// Manually set the offset Huffman Tree with a [`HuffmanTree`].
+    /// ```
+    /// # use vpk0::{Encoder, format::HuffmanTree};
+    /// let tree: HuffmanTree = "(3, (7, 10))".parse().unwrap();
+    /// let compressed = Encoder::for_bytes(b"sam I am I am sam")
+    ///     .with_offsets_tree(&tree)
+    ///     .encode_to_vec();
+    /// ```
+    #[inline]
+    pub fn with_offsets_tree(&mut self, tree: &HuffmanTree) -> &mut Self {
+        self.trees.offsets = Some(UserTree::Parsed(tree.clone()));
         self
     }
 
@@ -151,7 +303,7 @@ impl<'a, R: Read> Encoder<'a, R> {
     /// else create the offset tree from the input data.
     #[inline]
     pub fn optional_offsets(&mut self, offsets: Option<&'a str>) -> &mut Self {
-        self.offsets = offsets;
+        self.trees.offsets = offsets.map(UserTree::Text);
         self
     }
 
@@ -164,11 +316,18 @@ impl<'a, R: Read> Encoder<'a, R> {
     ///     .with_lengths("((3, 5), (7, (12, 16))")
     ///     .encode_to_vec();
     /// ```
-    /// Note that the encoding will fail if there is an offset whose size in bits is larger
-    /// than the largest provided offset.
+    /// Note that the encoding will fail if there is a length whose size in bits is larger
+    /// than the largest provided length, unless [`extend_trees`](Encoder::extend_trees) is set.
     #[inline]
     pub fn with_lengths(&mut self, l: &'a str) -> &mut Self {
-        self.lengths = Some(l);
+        self.trees.lengths = Some(UserTree::Text(l));
+        self
+    }
+
+    /// Manually set the length Huffman Tree with a [`HuffmanTree`].
+    #[inline]
+    pub fn with_lengths_tree(&mut self, tree: &HuffmanTree) -> &mut Self {
+        self.trees.lengths = Some(UserTree::Parsed(tree.clone()));
         self
     }
 
@@ -176,10 +335,34 @@ impl<'a, R: Read> Encoder<'a, R> {
     /// else create the offset tree from the input data.
     #[inline]
     pub fn optional_lengths(&mut self, lengths: Option<&'a str>) -> &mut Self {
-        self.lengths = lengths;
+        self.trees.lengths = lengths.map(UserTree::Text);
+        self
+    }
+
+    /// Add leaves to the user-provided trees for any values that are too wide for them.
+    /// The original tree is kept, and the new leaves are grafted on where they add
+    /// the fewest bits. The added leaves are available from [`added_leaves`](Encoder::added_leaves).
+    /// ```
+    /// # use vpk0::Encoder;
+    /// let input = b"a long string that needs longer offsets than a tree of 1 and 2 has";
+    /// let mut encoder = Encoder::for_bytes(input);
+    /// let compressed = encoder.with_offsets("(1, 2)").extend_trees(true).encode_to_vec();
+    /// assert!(compressed.is_ok());
+    /// assert!(!encoder.added_leaves().offsets.is_empty());
+    /// ```
+    #[inline]
+    pub fn extend_trees(&mut self, extend: bool) -> &mut Self {
+        self.trees.extend = extend;
         self
     }
 
+    /// The leaves that [`extend_trees`](Encoder::extend_trees) added to the
+    /// user-provided trees in the last encoding
+    #[inline]
+    pub fn added_leaves(&self) -> &AddedLeaves {
+        &self.added_leaves
+    }
+
     /// Write debugging and diagnotic information to `log` while the input is
     /// being encoded.
     #[inline]
@@ -189,6 +372,66 @@ impl<'a, R: Read> Encoder<'a, R> {
         self
     }
 
+    /// Run only the LZSS stage of the encoding, and return the literals and copy-backs.
+    /// Use [`write_tokens`] to write them to a `vpk0` file.
+    /// ```
+    /// # use vpk0::{Encoder, format::Token};
+    /// let tokens = Encoder::for_bytes(b"sam I am I am sam").encode_to_tokens().unwrap();
+    /// assert_eq!(tokens[0], Token::Literal(b's'));
+    /// assert_eq!(tokens[6], Token::Match { offset: 5, length: 8 });
+    /// ```
+    pub fn encode_to_tokens(&mut self) -> Result<Vec<Token>, VpkError> {
+        let (lzss, _) = do_compress(self)?;
+        Ok(lzss.buf.iter().map(LzssByte::to_token).collect())
+    }
+
+    /// Encode the input as an edited version of the `original` file, and write it out to `wtr`.
+    /// See [`reencode_to_vec()`](Encoder::reencode_to_vec).
+    #[inline]
+    pub fn reencode_to_writer<W: Write>(
+        &mut self,
+        original: &DecodedTokens,
+        wtr: W,
+    ) -> Result<(), VpkError> {
+        do_reencode(self, original, wtr)
+    }
+
+    /// Encode the input as an edited version of the `original` file, and return the
+    /// compressed data in a `Vec<u8>`.
+    ///
+    /// The tokens of `original` before and after the changed bytes are reused as is.
+    /// Only the tokens that cover the changed bytes, or that copy from them, are parsed
+    /// again with the LZSS settings of this `Encoder`. The file is written with the method
+    /// and trees of `original`, unless other trees are set with [`with_offsets()`] or
+    /// [`with_lengths()`]. If the trees are too small for the new tokens, an error is returned,
+    /// unless [`extend_trees()`] is set.
+    ///
+    /// Without any changes, this gives back the original file if it has no padding or
+    /// trailing data, and if its values were written with the shortest code that fits them.
+    /// ```
+    /// # use vpk0::{Encoder, Decoder};
+    /// let compressed = Encoder::for_bytes(b"sam I am I am sam").encode_to_vec().unwrap();
+    /// let original = Decoder::for_bytes(&compressed).decode_tokens().unwrap();
+    ///
+    /// let unchanged = Encoder::for_bytes(&original.data).reencode_to_vec(&original).unwrap();
+    /// assert_eq!(unchanged, compressed);
+    ///
+    /// let edited = Encoder::for_bytes(b"sam I am I am Sam")
+    ///     .extend_trees(true)
+    ///     .reencode_to_vec(&original)
+    ///     .unwrap();
+    /// assert_eq!(vpk0::decode_bytes(&edited).unwrap(), b"sam I am I am Sam");
+    /// ```
+    ///
+    /// [`with_offsets()`]: Encoder::with_offsets
+    /// [`with_lengths()`]: Encoder::with_lengths
+    /// [`extend_trees()`]: Encoder::extend_trees
+    #[inline]
+    pub fn reencode_to_vec(&mut self, original: &DecodedTokens) -> Result<Vec<u8>, VpkError> {
+        let mut out = Vec::new();
+        self.reencode_to_writer(original, &mut out).map(|_| out)
+    }
+
     /// Start the encoding and write the compressed data out to `wtr`
     #[inline]
     pub fn encode_to_writer<W: Write>(&mut self, wtr: W) -> Result<(), VpkError> {
@@ -246,27 +489,169 @@ pub fn encode_bytes(bytes: &[u8]) -> Result<Vec<u8>, VpkError> {
     Encoder::for_reader(Cursor::new(bytes)).encode_to_vec()
 }
 
-fn do_encode<R: Read, W: Write>(opts: &mut Encoder<'_, R>, mut wtr: W) -> Result<(), VpkError> {
+fn do_encode<R: Read, W: Write>(opts: &mut Encoder<'_, R>, wtr: W) -> Result<(), VpkError> {
+    let (lzss, huff_maps) = do_compress(opts)?;
+    let huff_maps = match huff_maps {
+        Some(maps) => maps,
+        None => opts.trees.build(&lzss)?,
+    };
+
+    finish_encode(opts, opts.method, &lzss, &huff_maps, wtr)
+}
+
+fn do_reencode<R: Read, W: Write>(
+    opts: &mut Encoder<'_, R>,
+    original: &DecodedTokens,
+    wtr: W,
+) -> Result<(), VpkError> {
+    let mut data = Vec::new();
+    opts.rdr.read_to_end(&mut data)?;
+    let (lzss, huff_maps) = reencode::reencode(opts, original, &data)?;
+
+    finish_encode(opts, original.header.method, &lzss, &huff_maps, wtr)
+}
+
+/// Log the trees, record the added leaves, and write the `vpk0` file
+fn finish_encode<R: Read, W: Write>(
+    opts: &mut Encoder<'_, R>,
+    method: VpkMethod,
+    lzss: &LzssPass,
+    huff_maps: &EncodedMaps,
+    mut wtr: W,
+) -> Result<(), VpkError> {
+    if let Some(wtr) = opts.log.as_mut() {
+        writeln!(wtr, "Huff Offsets / Movebacks\n{}", huff_maps.offsets).map_err(VpkError::Log)?;
+        writeln!(wtr, "Huff Lengths / Size\n{}", huff_maps.lengths).map_err(VpkError::Log)?;
+        //writeln!(info_wtr, "{}", &lzss)?;
+    }
+
+    opts.added_leaves = AddedLeaves {
+        offsets: huff_maps.offsets.added.clone(),
+        lengths: huff_maps.lengths.added.clone(),
+    };
+
+    write_file(&mut wtr, method, lzss, huff_maps)
+}
+
+/// Run the LZSS stage of `opts`. Optimal parsing also builds the trees, so they are returned too.
+fn do_compress<R: Read>(
+    opts: &mut Encoder<'_, R>,
+) -> Result<(LzssPass, Option<EncodedMaps>), VpkError> {
     let Encoder {
         rdr,
         method,
         settings,
         ref mut log,
-        offsets,
-        lengths,
         backend,
+        trees,
+        lazy,
+        ref mut policy,
+        optimal,
+        ..
     } = opts;
 
-    let lzss = lzss::compress_rdr(rdr, *settings, *method, *backend, log)?;
-    let huff_maps = huffman::EncodedMaps::new(*offsets, *lengths, &lzss)?;
+    let mut lazy = *lazy;
+    let policy = match policy {
+        Some(p) => &mut **p,
+        None => &mut lazy as &mut dyn LazyPolicy,
+    };
 
-    if let Some(wtr) = log.as_mut() {
-        writeln!(wtr, "Huff Offsets / Movebacks\n{}", huff_maps.offsets)?;
-        writeln!(wtr, "Huff Lengths / Size\n{}", huff_maps.lengths)?;
-        //writeln!(info_wtr, "{}", &lzss)?;
+    if *optimal {
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data)?;
+        let lzss = lzss::compress_rdr(&data[..], *settings, *method, *backend, policy, log)?;
+        let maps = trees.build(&lzss)?;
+        let build = |pass: LzssPass| trees.build(&pass).map(|maps| (pass, maps));
+        optimal::optimize(&data, 0, *settings, *method, (lzss, maps), build, log)
+            .map(|(lzss, maps)| (lzss, Some(maps)))
+    } else {
+        let lzss = lzss::compress_rdr(rdr, *settings, *method, *backend, policy, log)?;
+        Ok((lzss, None))
+    }
+}
+
+/// Write a complete `vpk0` file for LZSS `tokens` to `wtr`
+///
+/// Each copy-back must match `method`: [`Token::Match`] for one sample files, and
+/// [`Token::TwoSampleMatch`] for two sample files. Copy-backs can't move back further
+/// than the start of the data. The trees are built from the tokens
+/// with [`TreeMode::Huffman`] if they are not provided.
+/// ```
+/// # use vpk0::{format::{Token, VpkMethod}, Encoder};
+/// let input = b"sam I am I am sam";
+/// let mut tokens = Encoder::for_bytes(input).encode_to_tokens().unwrap();
+/// // replace the first byte, which is also copied to the last word
+/// tokens[0] = Token::Literal(b'S');
+///
+/// let mut compressed = Vec::new();
+/// vpk0::write_tokens(&mut compressed, VpkMethod::OneSample, &tokens, None, None).unwrap();
+/// assert_eq!(vpk0::decode_bytes(&compressed).unwrap(), b"Sam I am I am Sam");
+/// ```
+pub fn write_tokens<W: Write>(
+    mut wtr: W,
+    method: VpkMethod,
+    tokens: &[Token],
+    offsets: Option<&HuffmanTree>,
+    lengths: Option<&HuffmanTree>,
+) -> Result<(), VpkError> {
+    let pass = token_pass(method, tokens.iter().copied())?;
+    let (offsets, lengths) = (
+        offsets.cloned().map(UserTree::Parsed),
+        lengths.cloned().map(UserTree::Parsed),
+    );
+    let maps = EncodedMaps::new(
+        offsets.as_ref(),
+        lengths.as_ref(),
+        TreeMode::Huffman,
+        MAX_CODE_LENGTH,
+        false,
+        &pass,
+    )?;
+
+    write_file(&mut wtr, method, &pass, &maps)
+}
+
+/// Check each of the `tokens` and collect them into an `LzssPass`
+fn token_pass<I>(method: VpkMethod, tokens: I) -> Result<LzssPass, VpkError>
+where
+    I: IntoIterator<Item = Token>,
+{
+    let tokens = tokens.into_iter();
+    let mut pass = LzssPass::new(tokens.size_hint().0);
+    let mut position = 0;
+    for token in tokens {
+        pass.add(check_token(token, method, position)?);
+        position += token.len();
+    }
+    pass.decompressed_size = Some(position.try_into()?);
+
+    Ok(pass)
+}
+
+/// Check that `token` can be written to a `method` file after `position` bytes of output
+fn check_token(token: Token, method: VpkMethod, position: usize) -> Result<LzssByte, VpkError> {
+    let byte = LzssByte::from_token(token, method).ok_or(VpkError::WrongTokenMethod(method))?;
+    if let Token::Literal(..) = token {
+        return Ok(byte);
+    }
+
+    // the first of two samples is always less than three, so that the decoder reads both
+    if let Token::TwoSampleMatch { offset, .. } = token {
+        let decodable = match offset {
+            OffsetSample::One(v) => v >= 3,
+            OffsetSample::Two(first, _) => first < 3,
+        };
+        if !decodable {
+            return Err(VpkError::InvalidOffsetSample(offset));
+        }
     }
 
-    write_file(&mut wtr, *method, &lzss, &huff_maps)
+    let move_back = token.move_back(method);
+    if move_back == 0 || move_back > position {
+        return Err(VpkError::BadLookBack(move_back, position));
+    }
+
+    Ok(byte)
 }
 
 fn write_file(
@@ -277,8 +662,9 @@ fn write_file(
 ) -> Result<(), VpkError> {
     let mut out = BitWriter::endian(wtr, BigEndian);
     let header = VpkHeader {
-        // TODO: error, or maybe remove the option from here...
-        size: encoded_data.decompressed_size.unwrap(),
+        size: encoded_data
+            .decompressed_size
+            .ok_or(VpkError::MissingDecompressedSize)?,
         method,
     };
 
@@ -294,7 +680,10 @@ fn write_file(
                 out.write(8, byte)?;
             }
             LzssByte::Encoded(length, offset) => {
-                let maps = &[(offset, &trees.offsets), (length, &trees.lengths)];
+                let maps = &[
+                    (offset, &trees.offsets, TreeKind::Offsets),
+                    (length, &trees.lengths, TreeKind::Lengths),
+                ];
 
                 out.write_bit(LzssSettings::ENCODED)?;
                 for &set in maps {
@@ -307,15 +696,18 @@ fn write_file(
 
                 let offsets = match sample {
                     TwoSample::One(offset) => {
-                        one_arr = [(offset, &trees.offsets)];
+                        one_arr = [(offset, &trees.offsets, TreeKind::Offsets)];
                         &one_arr[..]
                     }
                     TwoSample::Two { first, second } => {
-                        two_arr = [(first, &trees.offsets), (second, &trees.offsets)];
+                        two_arr = [
+                            (first, &trees.offsets, TreeKind::Offsets),
+                            (second, &trees.offsets, TreeKind::Offsets),
+                        ];
                         &two_arr[..]
                     }
                 };
-                let length = [(length, &trees.lengths)];
+                let length = [(length, &trees.lengths, TreeKind::Lengths)];
 
                 out.write_bit(LzssSettings::ENCODED)?;
                 for &set in offsets.iter().chain(&length) {
@@ -332,11 +724,12 @@ fn write_file(
 
 fn write_encoded_val(
     out: &mut BitWriter<&mut dyn Write, BigEndian>,
-    (val, map): (usize, &MapTree),
+    (val, map, kind): (usize, &MapTree, TreeKind),
 ) -> Result<(), VpkError> {
     let needed_bits = count_needed_bits(val);
-    // TODO: replace unwrap with custom error
-    let (encoded_bits, code) = map.get(needed_bits).unwrap();
+    let (encoded_bits, code) = map
+        .get(needed_bits)
+        .ok_or_else(|| VpkError::MissingTreeCode(needed_bits).in_tree(kind))?;
     out.write(code.bitlen(), code.code)?;
     out.write(encoded_bits as u32, val as u32)?;
 
@@ -378,3 +771,52 @@ impl From<usize> for TwoSample {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pass_with(bytes: Vec<LzssByte>) -> LzssPass {
+        let mut pass = LzssPass::new(0);
+        for byte in bytes {
+            pass.add(byte);
+        }
+        pass.decompressed_size = Some(0);
+        pass
+    }
+
+    #[test]
+    fn missing_decompressed_size() {
+        let mut pass = pass_with(vec![LzssByte::Encoded(3, 1)]);
+        let maps =
+            EncodedMaps::new(None, None, TreeMode::Huffman, MAX_CODE_LENGTH, false, &pass).unwrap();
+        pass.decompressed_size = None;
+
+        let err = write_file(&mut Vec::new(), VpkMethod::OneSample, &pass, &maps).unwrap_err();
+        assert!(matches!(err, VpkError::MissingDecompressedSize), "{}", err);
+    }
+
+    #[test]
+    fn missing_tree_code() {
+        // the trees only have codes for the values in the first pass
+        let small = pass_with(vec![LzssByte::Encoded(3, 1)]);
+        let maps = EncodedMaps::new(
+            None,
+            None,
+            TreeMode::Huffman,
+            MAX_CODE_LENGTH,
+            false,
+            &small,
+        )
+        .unwrap();
+
+        let large = pass_with(vec![LzssByte::Encoded(3, 1000)]);
+        let err = write_file(&mut Vec::new(), VpkMethod::OneSample, &large, &maps).unwrap_err();
+        assert!(
+            matches!(err.kind(), VpkError::MissingTreeCode(10)),
+            "{}",
+            err
+        );
+        assert_eq!(err.tree(), Some(TreeKind::Offsets));
+    }
+}
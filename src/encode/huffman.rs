@@ -1,24 +1,58 @@
 use crate::{
-    encode::{BitSize, Frequency, LzssPass},
+    encode::{BitSize, Frequency, LzssPass, TreeMode},
     errors::EncodeTreeParseErr,
 };
 use crate::{
-    errors::VpkError,
-    format::{TreeEntry, VpkTree},
+    errors::{TreeKind, VpkError},
+    format::{HuffmanTree, TreeEntry, VpkTree},
 };
 use std::{
     cmp::Ordering,
-    collections::{BinaryHeap, HashMap},
+    collections::{BTreeMap, BinaryHeap},
     fmt,
-    mem::size_of,
+    iter::Peekable,
+    mem::{self, size_of},
     str::FromStr,
 };
 
 use smallvec::{smallvec, SmallVec};
 
 type SizeFreq = (BitSize, Frequency);
+
+/// The longest Huffman code that can be written
+pub(super) const MAX_CODE_LENGTH: u8 = HuffmanTree::MAX_CODE_LEN as u8;
+
 // size in bits => (bit size for encoded value, huffcode prefix prior encoded value bitsize)
-pub(super) type CodeMap = HashMap<BitSize, (BitSize, HuffCode)>;
+pub(super) type CodeMap = BTreeMap<BitSize, (BitSize, HuffCode)>;
+
+/// Build a tree for the found bit sizes with `mode`
+pub(crate) fn build_tree(found: &BTreeMap<BitSize, Frequency>, mode: TreeMode) -> VpkTree {
+    Tree::from_found_codes(found, mode, MAX_CODE_LENGTH)
+        .map(Into::into)
+        .unwrap_or_else(VpkTree::empty)
+}
+
+/// Parse a tree string, like a user tree for the encoder
+pub(crate) fn parse_vpk_tree(s: &str) -> Result<VpkTree, EncodeTreeParseErr> {
+    parse_treestr(s).map(Into::into)
+}
+
+/// A tree set by the user, as text or as a [`HuffmanTree`]
+#[derive(Debug, Clone)]
+pub(super) enum UserTree<'a> {
+    Text(&'a str),
+    Parsed(HuffmanTree),
+}
+
+impl UserTree<'_> {
+    /// Parse the tree, or `None` for an empty tree
+    fn tree(&self) -> Result<Option<Tree>, EncodeTreeParseErr> {
+        match self {
+            UserTree::Text(s) => parse_treestr(s).map(Some),
+            UserTree::Parsed(tree) => Ok(Tree::from_vpk(tree.as_vpk())),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub(super) struct EncodedMaps {
@@ -29,22 +63,25 @@ pub(super) struct EncodedMaps {
 }
 
 impl EncodedMaps {
-    ///
     pub(super) fn new(
-        offsets: Option<&str>,
-        lengths: Option<&str>,
+        offsets: Option<&UserTree>,
+        lengths: Option<&UserTree>,
+        mode: TreeMode,
+        max_code_length: u8,
+        extend: bool,
         p1: &LzssPass,
     ) -> Result<Self, VpkError> {
-        let offsets = offsets
-            .map(str::parse::<MapTree>)
-            .map(|t| t.map(|t| t.fill_missing(&p1.moveback_bitfreq)))
-            .transpose()?
-            .unwrap_or_else(|| Tree::from_found_codes(&p1.moveback_bitfreq).into());
-        let lengths = lengths
-            .map(str::parse::<MapTree>)
-            .map(|t| t.map(|t| t.fill_missing(&p1.size_bitfreq)))
-            .transpose()?
-            .unwrap_or_else(|| Tree::from_found_codes(&p1.size_bitfreq).into());
+        let build = |user: Option<&UserTree>, found, kind| match user {
+            Some(user) if extend => MapTree::extended(user.tree()?, found, mode, max_code_length)
+                .map_err(|e| e.in_tree(kind)),
+            Some(user) => MapTree::from_tree(user.tree()?)
+                .and_then(|tree| tree.fill_missing(found))
+                .map_err(|e| e.in_tree(kind)),
+            None => MapTree::from_tree(Tree::from_found_codes(found, mode, max_code_length))
+                .map_err(|e| e.in_tree(kind)),
+        };
+        let offsets = build(offsets, &p1.moveback_bitfreq, TreeKind::Offsets)?;
+        let lengths = build(lengths, &p1.size_bitfreq, TreeKind::Lengths)?;
 
         Ok(Self { offsets, lengths })
     }
@@ -54,6 +91,8 @@ impl EncodedMaps {
 pub(super) struct MapTree {
     map: CodeMap,
     pub tree: VpkTree,
+    // leaves grafted onto a user tree
+    pub added: Vec<BitSize>,
 }
 
 impl MapTree {
@@ -61,18 +100,26 @@ impl MapTree {
         self.map.get(&bitsize).copied()
     }
 
-    fn fill_missing(mut self, found: &HashMap<BitSize, Frequency>) -> Self {
-        // TODO: errors?
-        let max = self
-            .map
-            .keys()
-            .copied()
-            .max()
-            .expect("at least one bit size in MapTree");
+    /// The total bits needed to write a value of each bit size with this tree,
+    /// or `None` if a bit size is larger than every leaf
+    pub(super) fn bit_costs(&self) -> [Option<u64>; 65] {
+        let mut costs = [None; 65];
+        let mut next = None;
+        for (size, cost) in costs.iter_mut().enumerate().rev() {
+            if let Some(&(encoded, code)) = self.map.get(&(size as BitSize)) {
+                next = Some(code.bitlen() as u64 + encoded as u64);
+            }
+            *cost = next;
+        }
+        costs
+    }
+
+    fn fill_missing(mut self, found: &BTreeMap<BitSize, Frequency>) -> Result<Self, VpkError> {
+        let max = self.map.keys().copied().max().unwrap_or(0);
 
         for &bitsize in found.keys() {
             if bitsize > max {
-                panic!("tried to insert {} into tree with max of {}", bitsize, max)
+                return Err(VpkError::UserTreeTooSmall(bitsize, max));
             }
 
             let mut check = bitsize;
@@ -86,7 +133,44 @@ impl MapTree {
             }
         }
 
-        self
+        Ok(self)
+    }
+
+    /// Graft a subtree onto a user tree for the found bit sizes that are larger
+    /// than every leaf. The subtree is built with `mode`, and replaces the node
+    /// where it adds the fewest bits to the encoded values.
+    /// An empty user tree is replaced by the subtree.
+    fn extended(
+        mut tree: Option<Tree>,
+        found: &BTreeMap<BitSize, Frequency>,
+        mode: TreeMode,
+        max_code_length: u8,
+    ) -> Result<Self, VpkError> {
+        let largest = tree.as_ref().map(|t| t.root.max_size());
+        let needed: BTreeMap<BitSize, Frequency> = found
+            .iter()
+            .filter(|&(&size, _)| largest.map_or(true, |l| size > l))
+            .map(|(&size, &freq)| (size, freq))
+            .collect();
+
+        let limit = max_code_length.min(MAX_CODE_LENGTH - 1);
+        let added = match (Tree::from_found_codes(&needed, mode, limit), &mut tree) {
+            (Some(sub), Some(tree)) => {
+                let added = sub.root.leaf_sizes();
+                tree.graft(sub.root, found)?;
+                added
+            }
+            (Some(sub), None) => {
+                let added = sub.root.leaf_sizes();
+                tree = Some(sub);
+                added
+            }
+            (None, _) => Vec::new(),
+        };
+
+        let mut map = MapTree::from_tree(tree)?.fill_missing(found)?;
+        map.added = added;
+        Ok(map)
     }
 
     /// Create an empty Tree (i.e., no found matches in a buffer)
@@ -94,6 +178,7 @@ impl MapTree {
         Self {
             map: CodeMap::new(),
             tree: VpkTree::empty(),
+            added: Vec::new(),
         }
     }
 }
@@ -111,26 +196,25 @@ impl fmt::Display for MapTree {
     }
 }
 
-impl From<Option<Tree>> for MapTree {
-    fn from(opt: Option<Tree>) -> Self {
-        opt.map(|tree| {
-            let map = tree.generate_code_map();
-            let tree = tree.into();
-            Self { map, tree }
-        })
-        .unwrap_or_else(Self::empty)
+impl MapTree {
+    fn from_tree(opt: Option<Tree>) -> Result<Self, VpkError> {
+        let tree = match opt {
+            Some(tree) => tree,
+            None => return Ok(Self::empty()),
+        };
+        let map = tree.generate_code_map()?;
+        let tree = tree.into();
+        let added = Vec::new();
+
+        Ok(Self { map, tree, added })
     }
 }
 
 impl FromStr for MapTree {
-    type Err = EncodeTreeParseErr;
+    type Err = VpkError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let t = parse_treestr(s)?;
-        let map = t.generate_code_map();
-        let tree = t.into();
-
-        Ok(Self { map, tree })
+        Self::from_tree(Some(parse_treestr(s)?))
     }
 }
 
@@ -157,18 +241,157 @@ impl Tree {
         Some(Self { root })
     }
 
-    fn generate_code_map(&self) -> CodeMap {
-        let mut map = HashMap::new();
+    /// Find the Huffman code for each bit size, or return [`VpkError::TreeTooDeep`]
+    /// if a code doesn't fit in a [`HuffCode`]
+    fn generate_code_map(&self) -> Result<CodeMap, VpkError> {
+        let depth = self.root.depth();
+        if depth > HuffCode::MAX_SIZE {
+            return Err(VpkError::TreeTooDeep(depth as u32));
+        }
+
+        let mut map = CodeMap::new();
         self.root.generate_code(HuffCode::new(), &mut map);
-        map
+        Ok(map)
     }
 
-    fn from_found_codes(map: &HashMap<BitSize, Frequency>) -> Option<Self> {
-        let copied_tupple = |(&a, &b)| (a, b);
+    /// Replace the node where `sub` is cheapest to add with a node of the old node and `sub`.
+    /// Every value under the old node needs one more bit, and every value in `sub`
+    /// needs a bit for each level above it. Returns [`VpkError::TreeTooDeep`] if every
+    /// graft makes a code longer than [`MAX_CODE_LENGTH`].
+    fn graft(
+        &mut self,
+        sub: TreeNode,
+        found: &BTreeMap<BitSize, Frequency>,
+    ) -> Result<(), VpkError> {
+        // how often each leaf is used by the found bit sizes that the tree already covers
+        let map = self.generate_code_map()?;
+        let mut leaf_freq = BTreeMap::new();
+        for (&size, &freq) in found {
+            if let Some((&leaf, _)) = map.range(size..).next() {
+                *leaf_freq.entry(leaf).or_insert(0) += freq;
+            }
+        }
 
-        let heap = map.iter().map(copied_tupple).map(TreeNode::from).collect();
+        let mut path = Vec::new();
+        let mut best = None;
+        let mut shortest = usize::MAX;
+        self.root.find_graft(
+            &leaf_freq,
+            (sub.freq(), sub.depth()),
+            MAX_CODE_LENGTH as usize,
+            &mut path,
+            &mut best,
+            &mut shortest,
+        );
+        let path = match best {
+            Some((_, path)) => path,
+            None => return Err(VpkError::TreeTooDeep(shortest as u32)),
+        };
 
-        Self::from_heap(heap)
+        let mut node = &mut self.root;
+        for &right in path.iter() {
+            node = match node {
+                TreeNode::Node { left, right: r, .. } => {
+                    if right {
+                        r
+                    } else {
+                        left
+                    }
+                }
+                _ => unreachable!("graft path only goes through nodes"),
+            };
+        }
+        let old = mem::replace(node, TreeNode::Leaf { size: 0, freq: 0 });
+        *node = TreeNode::Node {
+            freq: old.freq() + sub.freq(),
+            left: Box::new(old),
+            right: Box::new(sub),
+        };
+
+        Ok(())
+    }
+
+    /// Build a tree for the found bit sizes with `mode`. If that tree has a code
+    /// longer than `max_code_length` bits, build a length limited tree instead.
+    fn from_found_codes(
+        map: &BTreeMap<BitSize, Frequency>,
+        mode: TreeMode,
+        max_code_length: u8,
+    ) -> Option<Self> {
+        let sizes: Vec<SizeFreq> = map.iter().map(|(&a, &b)| (a, b)).collect();
+
+        let tree = match mode {
+            TreeMode::Huffman => {
+                let heap = sizes.iter().copied().map(TreeNode::from).collect();
+                Self::from_heap(heap)
+            }
+            TreeMode::Nintendo => Self::from_sorted_sizes(&sizes),
+        }?;
+
+        let max_code_length = max_code_length.min(MAX_CODE_LENGTH);
+        if tree.root.depth() <= max_code_length as usize {
+            Some(tree)
+        } else {
+            Self::from_limited_sizes(&sizes, max_code_length)
+        }
+    }
+
+    /// Build a tree with no code longer than `limit` bits from bit sizes sorted from
+    /// smallest to largest. Neighboring bit sizes are combined into one leaf, as cheaply
+    /// as possible, until there are few enough leaves. Then the code lengths are
+    /// found with the package-merge algorithm.
+    fn from_limited_sizes(sizes: &[SizeFreq], limit: u8) -> Option<Self> {
+        let freq = |group: &[SizeFreq]| group.iter().map(|&(_, f)| f).sum::<Frequency>();
+        let largest = |group: &[SizeFreq]| group[group.len() - 1].0 as Frequency;
+
+        let mut groups: Vec<Vec<SizeFreq>> = sizes.iter().map(|&sf| vec![sf]).collect();
+        while groups.len() > 1 << limit {
+            // every value in the smaller group now needs the bits of the larger group
+            let (_, i) = groups
+                .windows(2)
+                .enumerate()
+                .map(|(i, w)| (freq(&w[0]) * (largest(&w[1]) - largest(&w[0])), i))
+                .min()?;
+            let smaller = groups.remove(i);
+            groups[i].splice(0..0, smaller);
+        }
+
+        let weights: Vec<Frequency> = groups.iter().map(|g| freq(g)).collect();
+        let lengths = package_merge(&weights, limit);
+
+        let mut leaves: Vec<(u8, TreeNode)> = lengths
+            .into_iter()
+            .zip(groups)
+            .map(|(len, group)| {
+                let (largest, lesser) = group.split_last().unwrap();
+                let node = if lesser.is_empty() {
+                    TreeNode::from(*largest)
+                } else {
+                    TreeNode::CombinedLeaf {
+                        size: largest.0,
+                        freq: freq(&group),
+                        lesser: lesser.iter().map(|&(size, _)| size).collect(),
+                    }
+                };
+                (len, node)
+            })
+            .collect();
+        leaves.sort_by_key(|&(len, _)| len);
+
+        let mut leaves = leaves.into_iter().peekable();
+        let root = from_code_lengths(0, &mut leaves);
+        Some(Self { root })
+    }
+
+    /// Build a tree from bit sizes sorted from smallest to largest by recursively
+    /// splitting them in two. This matches the trees made by Nintendo's encoder.
+    fn from_sorted_sizes(sizes: &[SizeFreq]) -> Option<Self> {
+        if sizes.is_empty() {
+            None
+        } else {
+            let root = split_sizes(sizes);
+            Some(Self { root })
+        }
     }
 
     /*
@@ -200,6 +423,30 @@ impl FromIterator<SizeFreq> for Option<Tree> {
 }
 */
 
+impl Tree {
+    /// Convert a decoded tree, or return `None` for an empty tree
+    fn from_vpk(tree: &VpkTree) -> Option<Self> {
+        fn node(entries: &[TreeEntry], idx: usize) -> TreeNode {
+            match entries[idx] {
+                TreeEntry::Leaf(size) => TreeNode::Leaf { size, freq: 0 },
+                TreeEntry::Node { left, right } => TreeNode::Node {
+                    freq: 0,
+                    left: Box::new(node(entries, left)),
+                    right: Box::new(node(entries, right)),
+                },
+            }
+        }
+
+        let entries = tree.entries();
+        match entries.len() {
+            0 => None,
+            len => Some(Self {
+                root: node(entries, len - 1),
+            }),
+        }
+    }
+}
+
 #[allow(clippy::from_over_into)]
 impl Into<VpkTree> for Tree {
     fn into(self) -> VpkTree {
@@ -242,6 +489,89 @@ impl TreeNode {
         }
     }
 
+    /// The largest bit size of the leaves in this subtree
+    fn max_size(&self) -> BitSize {
+        match self {
+            Self::Leaf { size, .. } | Self::CombinedLeaf { size, .. } => *size,
+            Self::Node { left, right, .. } => left.max_size().max(right.max_size()),
+        }
+    }
+
+    /// The bit sizes of the leaves in this subtree, from left to right
+    fn leaf_sizes(&self) -> Vec<BitSize> {
+        match self {
+            Self::Leaf { size, .. } | Self::CombinedLeaf { size, .. } => vec![*size],
+            Self::Node { left, right, .. } => {
+                let mut sizes = left.leaf_sizes();
+                sizes.extend(right.leaf_sizes());
+                sizes
+            }
+        }
+    }
+
+    /// Find the path to the node where grafting a subtree of `(frequency, depth)` costs the
+    /// fewest bits without making a code longer than `max_len`, and return how often the
+    /// leaves under this node are used and the depth of this node.
+    /// `shortest` is set to the smallest longest code length of every possible graft.
+    fn find_graft(
+        &self,
+        leaf_freq: &BTreeMap<BitSize, Frequency>,
+        (new_freq, new_depth): (Frequency, usize),
+        max_len: usize,
+        path: &mut Vec<bool>,
+        best: &mut Option<(Frequency, Vec<bool>)>,
+        shortest: &mut usize,
+    ) -> (Frequency, usize) {
+        let (freq, height) = match self {
+            Self::Leaf { size, .. } | Self::CombinedLeaf { size, .. } => {
+                (leaf_freq.get(size).copied().unwrap_or(0), 0)
+            }
+            Self::Node { left, right, .. } => {
+                let (mut freq, mut height) = (0, 0);
+                for (child, side) in [(left, false), (right, true)].iter() {
+                    path.push(*side);
+                    let sub = (new_freq, new_depth);
+                    let (f, h) = child.find_graft(leaf_freq, sub, max_len, path, best, shortest);
+                    path.pop();
+                    freq += f;
+                    height = height.max(h + 1);
+                }
+                (freq, height)
+            }
+        };
+
+        // both this node and the new subtree move one level below the new node
+        let depth = path.len();
+        let longest = depth + 1 + height.max(new_depth);
+        *shortest = longest.min(*shortest);
+        let cost = freq + new_freq * depth as Frequency;
+        if longest <= max_len && best.as_ref().map_or(true, |(c, _)| cost < *c) {
+            *best = Some((cost, path.clone()));
+        }
+
+        (freq, height)
+    }
+
+    /// The length of the longest code in this subtree
+    fn depth(&self) -> usize {
+        match self {
+            Self::Node { left, right, .. } => 1 + left.depth().max(right.depth()),
+            _ => 0,
+        }
+    }
+
+    /// The smallest bit size in this subtree, which is unique to each subtree
+    /// and breaks ties between equal frequencies
+    fn min_size(&self) -> BitSize {
+        match self {
+            Self::Leaf { size, .. } => *size,
+            Self::CombinedLeaf { size, lesser, .. } => {
+                lesser.iter().copied().fold(*size, BitSize::min)
+            }
+            Self::Node { left, right, .. } => left.min_size().min(right.min_size()),
+        }
+    }
+
     fn lessers(&self) -> Option<&[BitSize]> {
         match self {
             Self::CombinedLeaf { lesser, .. } => Some(lesser),
@@ -300,9 +630,15 @@ impl From<SizeFreq> for TreeNode {
     }
 }
 
+// `BinaryHeap` is a max-heap, so the order is reversed to pop the least frequent node first.
+// Nodes with the same frequency are popped by smallest bit size, so that the tree
+// is always the same for the same frequencies.
 impl Ord for TreeNode {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.freq().cmp(&other.freq()).reverse()
+        self.freq()
+            .cmp(&other.freq())
+            .then_with(|| self.min_size().cmp(&other.min_size()))
+            .reverse()
     }
 }
 
@@ -314,12 +650,100 @@ impl PartialOrd for TreeNode {
 
 impl PartialEq for TreeNode {
     fn eq(&self, other: &Self) -> bool {
-        self.freq() == other.freq()
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl Eq for TreeNode {}
 
+// Turn a sorted range of bit sizes into either a single leaf, which uses the largest bit size
+// for every value in the range, or a node with the range split in two.
+// Each possible split is checked as if both halves were leaves, and the range is
+// only split if the cheapest split (the first one on ties) uses fewer bits than a leaf.
+fn split_sizes(sizes: &[SizeFreq]) -> TreeNode {
+    let freq = |range: &[SizeFreq]| range.iter().map(|&(_, f)| f).sum::<Frequency>();
+    let leaf_cost = |range: &[SizeFreq]| freq(range) * range[range.len() - 1].0 as Frequency;
+
+    let total = freq(sizes);
+    let split = (1..sizes.len())
+        .map(|m| (leaf_cost(&sizes[..m]) + leaf_cost(&sizes[m..]) + total, m))
+        .min();
+
+    match split {
+        Some((cost, m)) if cost < leaf_cost(sizes) => TreeNode::Node {
+            freq: total,
+            left: Box::new(split_sizes(&sizes[..m])),
+            right: Box::new(split_sizes(&sizes[m..])),
+        },
+        _ => {
+            let (largest, lesser) = sizes.split_last().unwrap();
+            if lesser.is_empty() {
+                TreeNode::from(*largest)
+            } else {
+                TreeNode::CombinedLeaf {
+                    size: largest.0,
+                    freq: total,
+                    lesser: lesser.iter().map(|&(size, _)| size).collect(),
+                }
+            }
+        }
+    }
+}
+
+// Find the optimal code length for each weight, where no code is longer than `limit` bits.
+// There must be at most `1 << limit` weights.
+fn package_merge(weights: &[Frequency], limit: u8) -> Vec<u8> {
+    let n = weights.len();
+    if n < 2 {
+        return vec![0; n];
+    }
+
+    // each item is a weight and the indices of the weights it contains
+    let mut leaves: Vec<(Frequency, Vec<usize>)> = weights
+        .iter()
+        .enumerate()
+        .map(|(i, &w)| (w, vec![i]))
+        .collect();
+    leaves.sort_by_key(|&(w, _)| w);
+
+    let mut items = leaves.clone();
+    for _ in 1..limit {
+        let packages = items
+            .chunks_exact(2)
+            .map(|p| (p[0].0 + p[1].0, [&p[0].1[..], &p[1].1[..]].concat()));
+        items = leaves.iter().cloned().chain(packages).collect();
+        // stable, so leaves come before packages with the same weight
+        items.sort_by_key(|&(w, _)| w);
+    }
+
+    // each time a weight is in one of the 2n - 2 cheapest items, its code is one bit longer
+    let mut lengths = vec![0; n];
+    for (_, indices) in &items[..2 * n - 2] {
+        for &i in indices {
+            lengths[i] += 1;
+        }
+    }
+    lengths
+}
+
+// Build a tree from leaves sorted by their code lengths, which must form a complete tree
+fn from_code_lengths<I>(depth: u8, leaves: &mut Peekable<I>) -> TreeNode
+where
+    I: Iterator<Item = (u8, TreeNode)>,
+{
+    if leaves.peek().map_or(false, |&(len, _)| len == depth) {
+        return leaves.next().unwrap().1;
+    }
+
+    let left = from_code_lengths(depth + 1, leaves);
+    let right = from_code_lengths(depth + 1, leaves);
+    TreeNode::Node {
+        freq: left.freq() + right.freq(),
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
 // try save bits if two `TreeNode::Leaf`s are next to each other in the tree
 fn pair_lesser_sizes(l: &TreeNode, r: &TreeNode) -> Option<TreeNode> {
     order_leaves(l, r).and_then(|(higher, lower)| {
@@ -372,10 +796,12 @@ impl HuffCode {
     }
 
     fn push(&mut self, bit: bool) {
+        // the depth of a tree is checked before its codes are generated
+        debug_assert!(
+            self.len() < Self::MAX_SIZE,
+            "exceded bit size for huffman code"
+        );
         self.size += 1;
-        if self.len() >= Self::MAX_SIZE {
-            panic!("exceded bit size for huffman code");
-        }
         self.code <<= 1;
         self.code |= bit as u32;
     }
@@ -451,7 +877,7 @@ fn lex_treestr(s: &str) -> Result<Vec<LexToken>, EncodeTreeParseErr> {
     let get_pos = |csr: &str| s.len() - csr.len();
     let count_ws = |s: &str| s.chars().take_while(|c| c.is_whitespace()).count();
     let parse_num = |s: &str| {
-        let n = s.chars().take_while(|c| c.is_digit(10)).count();
+        let n = s.chars().take_while(|c| c.is_ascii_digit()).count();
         s[..n].parse().map(Number).map(|t| (n, t))
     };
 
@@ -463,7 +889,7 @@ fn lex_treestr(s: &str) -> Result<Vec<LexToken>, EncodeTreeParseErr> {
             ')' => Ok((1, CloseParen)),
             ',' => Ok((1, Comma)),
             _ if c.is_whitespace() => Ok((count_ws(csr), Whitespace)),
-            _ if c.is_digit(10) => parse_num(csr).map_err(|e| E::LexNum(e, get_pos(csr))),
+            _ if c.is_ascii_digit() => parse_num(csr).map_err(|e| E::LexNum(e, get_pos(csr))),
             _ => Err(E::LexUnexp(c, get_pos(csr))),
         }?;
 
@@ -480,10 +906,10 @@ fn lex_treestr(s: &str) -> Result<Vec<LexToken>, EncodeTreeParseErr> {
 // node -> (node, node) | leaf
 // leaf -> NUMBER
 fn parse_tree(iter: &mut LexIter) -> Result<Tree, EncodeTreeParseErr> {
-    parse_node(iter).map(|root| Tree { root })
+    parse_node(iter, 0).map(|root| Tree { root })
 }
 
-fn parse_node(iter: &mut LexIter) -> Result<TreeNode, EncodeTreeParseErr> {
+fn parse_node(iter: &mut LexIter, depth: u8) -> Result<TreeNode, EncodeTreeParseErr> {
     use EncodeTreeParseErr as E;
     use Token::*;
 
@@ -492,8 +918,9 @@ fn parse_node(iter: &mut LexIter) -> Result<TreeNode, EncodeTreeParseErr> {
 
     match iter.next() {
         Some(LexToken(_, Number(size))) => Ok(TreeNode::Leaf { size, freq: 0 }),
+        Some(LexToken(pos, OpenParen)) if depth >= MAX_CODE_LENGTH => Err(E::TooDeep(pos)),
         Some(LexToken(_, OpenParen)) => {
-            let left = parse_node(iter)?;
+            let left = parse_node(iter, depth + 1)?;
 
             // check for comma
             match iter.next() {
@@ -506,7 +933,7 @@ fn parse_node(iter: &mut LexIter) -> Result<TreeNode, EncodeTreeParseErr> {
                 }
             };
 
-            let right = parse_node(iter)?;
+            let right = parse_node(iter, depth + 1)?;
 
             // check for end of node
             match iter.next() {
@@ -577,7 +1004,7 @@ mod test {
         // Nintendo's typical trees do not have a single entry for each possible bitsize in the file
         // so, these bitsizes need to be filled into the code based on existing huffman codes
         let inputs = &["(3, 5)", "(1, (4, 7))"];
-        let found_sizes: &[HashMap<BitSize, Frequency>] = &[
+        let found_sizes: &[BTreeMap<BitSize, Frequency>] = &[
             [(2, 5), (3, 8), (4, 4), (5, 1)].iter().copied().collect(),
             [(1, 8), (3, 1), (4, 4), (6, 3), (7, 2)]
                 .iter()
@@ -586,13 +1013,156 @@ mod test {
         ];
 
         for (s, found) in inputs.iter().zip(found_sizes) {
-            let tree = s.parse::<MapTree>()?.fill_missing(found);
+            let tree = s.parse::<MapTree>()?.fill_missing(found)?;
             println!("Tree for {}\n\t{:?}", s, tree);
         }
 
         Ok(())
     }
 
+    #[test]
+    fn equal_frequency_trees() {
+        let found: BTreeMap<BitSize, Frequency> =
+            [(3, 4), (5, 4), (7, 4), (9, 4), (11, 2), (12, 2)]
+                .iter()
+                .copied()
+                .collect();
+        // ties are broken by popping the smallest bit size first
+        let tree: VpkTree = Tree::from_found_codes(&found, TreeMode::Huffman, MAX_CODE_LENGTH)
+            .unwrap()
+            .into();
+        assert_eq!(tree.to_string(), "(9, (12, 5))");
+    }
+
+    #[test]
+    fn length_limited_trees() {
+        // fibonacci frequencies make the deepest possible Huffman tree
+        let mut fib = (1, 1);
+        let found: BTreeMap<BitSize, Frequency> = (1..=40)
+            .map(|size| {
+                fib = (fib.1, fib.0 + fib.1);
+                (size, fib.0)
+            })
+            .collect();
+
+        for &mode in &[TreeMode::Huffman, TreeMode::Nintendo] {
+            for &limit in &[MAX_CODE_LENGTH, 8, 5, 1, 0] {
+                let tree = Tree::from_found_codes(&found, mode, limit).unwrap();
+                assert!(tree.root.depth() <= limit as usize, "{:?} {}", mode, limit);
+
+                let map = MapTree::from_tree(Some(tree)).unwrap();
+                for &size in found.keys() {
+                    let (encoded, _) = map.get(size).unwrap();
+                    assert!(encoded >= size);
+                }
+            }
+        }
+
+        assert_eq!(package_merge(&[1, 2, 4, 8], 2), [2, 2, 2, 2]);
+        assert_eq!(package_merge(&[1, 1, 2, 4, 8], 3), [3, 3, 3, 3, 1]);
+        assert_eq!(package_merge(&[1, 1, 2, 4, 8], 4), [4, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn extending_user_tree() -> Result<(), VpkError> {
+        let found: BTreeMap<BitSize, Frequency> =
+            [(1, 100), (2, 1), (3, 1), (8, 5)].iter().copied().collect();
+        // grafting next to the rarely used (2, 3) node costs the fewest bits
+        let tree = MapTree::extended(
+            Some(parse_treestr("(1, (2, 3))")?),
+            &found,
+            TreeMode::Huffman,
+            MAX_CODE_LENGTH,
+        )?;
+        assert_eq!(tree.tree.to_string(), "(1, ((2, 3), 8))");
+        assert_eq!(tree.added, [8]);
+        assert_eq!(tree.get(8).unwrap().1.bitlen(), 2);
+
+        // nothing is added when the tree already covers every size
+        let tree = MapTree::extended(
+            Some(parse_treestr("(1, (2, 8))")?),
+            &found,
+            TreeMode::Huffman,
+            MAX_CODE_LENGTH,
+        )?;
+        assert_eq!(tree.tree.to_string(), "(1, (2, 8))");
+        assert!(tree.added.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn extending_deep_user_tree() {
+        // a tree with a code of the longest length, and a leaf next to the root
+        let depth = MAX_CODE_LENGTH as usize;
+        let deep = format!("{}1{}", "(".repeat(depth), ", 2)".repeat(depth));
+
+        let found: BTreeMap<BitSize, Frequency> = [(1, 10), (8, 5)].iter().copied().collect();
+        let tree = MapTree::extended(
+            parse_treestr(&deep).ok(),
+            &found,
+            TreeMode::Huffman,
+            MAX_CODE_LENGTH,
+        )
+        .unwrap();
+        assert_eq!(tree.added, [8]);
+        assert_eq!(tree.get(8).unwrap().1.bitlen(), 2);
+
+        // a subtree with the longest codes allowed doesn't fit anywhere
+        let mut tree = parse_treestr(&deep).unwrap();
+        let sub = deep_node(TreeNode::Leaf { size: 40, freq: 1 }, MAX_CODE_LENGTH - 1);
+        let err = tree.graft(sub, &found).unwrap_err();
+        assert!(
+            matches!(err, VpkError::TreeTooDeep(len) if len == MAX_CODE_LENGTH as u32 + 1),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn too_deep_user_tree() {
+        let deep = format!("{}1{}", "(".repeat(33), ", 2)".repeat(33));
+        let err = match parse_treestr(&deep) {
+            Err(err @ EncodeTreeParseErr::TooDeep(32)) => err,
+            _ => panic!("a tree with 33 bit codes was parsed"),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Tree has codes longer than 32 bits at pos 32"
+        );
+
+        let limit = format!("{}1{}", "(".repeat(32), ", 2)".repeat(32));
+        assert!(parse_treestr(&limit).is_ok());
+    }
+
+    /// Put `root` under `depth` nodes, each with a leaf on its left
+    fn deep_node(root: TreeNode, depth: u8) -> TreeNode {
+        (0..depth).fold(root, |node, size| TreeNode::Node {
+            freq: 0,
+            left: Box::new(TreeNode::Leaf { size, freq: 0 }),
+            right: Box::new(node),
+        })
+    }
+
+    #[test]
+    fn too_deep_code_map() {
+        let leaf = TreeNode::Leaf { size: 40, freq: 0 };
+        let limit = Tree {
+            root: deep_node(leaf, HuffCode::MAX_SIZE as u8),
+        };
+        let map = limit.generate_code_map().unwrap();
+        assert_eq!(map[&40].1.bitlen(), 32);
+
+        let leaf = TreeNode::Leaf { size: 40, freq: 0 };
+        let deep = Tree {
+            root: deep_node(leaf, HuffCode::MAX_SIZE as u8 + 1),
+        };
+        assert!(matches!(
+            deep.generate_code_map(),
+            Err(VpkError::TreeTooDeep(33))
+        ));
+    }
+
     fn check_tree_parsing(s: &str, parsed: &[(BitSize, u32)]) {
         let tree = match parse_treestr(s) {
             Ok(t) => t,
@@ -608,7 +1178,7 @@ mod test {
                 );
             }
         };
-        let map = tree.generate_code_map();
+        let map = tree.generate_code_map().unwrap();
         for (key, expected) in parsed {
             let found = map.get(key);
             assert!(
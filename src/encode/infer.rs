@@ -0,0 +1,227 @@
+//! Find the encoder settings that were used to create an existing `vpk0` file,
+//! by encoding its data again with each candidate and comparing the tokens.
+
+use super::{
+    count_needed_bits,
+    huffman::{EncodedMaps, MAX_CODE_LENGTH},
+    lzss::{LazyMatching, LzssSettings},
+    token_pass, Encoder, LzssBackend, TreeMode,
+};
+use crate::{
+    decode::{DecodedTokens, Decoder},
+    errors::VpkError,
+    format::{HuffmanTrees, Token, VpkMethod},
+};
+use std::io::Cursor;
+
+/// The match finders that are checked, from the fastest. [`LzssBackend::BinaryTree`] finds
+/// the same matches as [`LzssBackend::Brute`] and [`LzssBackend::KmpAhead`], and is much faster.
+/// [`LzssBackend::Kmp`] is only checked if no settings match with the binary tree.
+const BACKENDS: &[LzssBackend] = &[LzssBackend::BinaryTree, LzssBackend::Kmp];
+
+/// The deepest [`LazyMatching::Nintendo`] look ahead that is checked
+const MAX_LAZY_DEPTH: usize = 16;
+
+/// The encoder settings found by [`infer_settings`]
+///
+/// If no combination of settings gives the same tokens as the file,
+/// these are the settings that match the most tokens from the start of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InferredSettings {
+    pub method: VpkMethod,
+    pub settings: LzssSettings,
+    pub backend: LzssBackend,
+    pub lazy: LazyMatching,
+    /// The trees stored in the file
+    pub trees: HuffmanTrees,
+    /// A [`TreeMode`] that builds the same trees from the tokens, if there is one
+    pub tree_mode: Option<TreeMode>,
+    /// The first token that is different when the data is encoded with these settings,
+    /// or `None` if all of the tokens are the same
+    pub mismatch: Option<TokenMismatch>,
+    /// `true` if encoding with these settings and trees gives back the same bytes as the file
+    pub exact: bool,
+}
+
+/// The first difference between the tokens of a file and the tokens from encoding its data again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenMismatch {
+    /// The index of the token
+    pub index: usize,
+    /// The token in the file, or `None` if the encoder output more tokens
+    pub expected: Option<Token>,
+    /// The token from the encoder, or `None` if the file has more tokens
+    pub found: Option<Token>,
+}
+
+impl InferredSettings {
+    /// Create an [`Encoder`] for `data` with these settings and the trees from the file.
+    pub fn encoder<'a>(&self, data: &'a [u8]) -> Encoder<'a, Cursor<&'a [u8]>> {
+        let mut encoder = Encoder::for_bytes(data);
+        encoder
+            .method(self.method)
+            .with_lzss_settings(self.settings)
+            .lzss_backend(self.backend)
+            .lazy_matching(self.lazy)
+            .with_offsets_tree(&self.trees.offsets)
+            .with_lengths_tree(&self.trees.lengths);
+
+        encoder
+    }
+}
+
+/// Find the [`VpkMethod`], [`LzssSettings`], [`LzssBackend`], [`LazyMatching`],
+/// and trees that reproduce the `vpk0` file in `bytes`
+///
+/// The decoded data is encoded again with each combination of the LZSS settings that allow
+/// the copy-backs in the file and each built-in lazy matching policy, until one gives the same
+/// tokens as the file. This is first done with the [`BinaryTree`](LzssBackend::BinaryTree)
+/// backend, which finds the same matches as [`Brute`](LzssBackend::Brute) and
+/// [`KmpAhead`](LzssBackend::KmpAhead), then with [`Kmp`](LzssBackend::Kmp).
+///
+/// This can be slow: every combination is a full encode of the data, so a file that
+/// doesn't match any settings is encoded a few hundred times, and the `Kmp` encodes
+/// take time proportional to the data size times the window size.
+/// ```
+/// # use vpk0::{Encoder, LazyMatching};
+/// let data = b"sam I am I am sam, sam I am";
+/// let compressed = Encoder::for_bytes(data)
+///     .lazy_matching(LazyMatching::Greedy)
+///     .encode_to_vec()
+///     .unwrap();
+///
+/// let inferred = vpk0::infer_settings(&compressed).unwrap();
+/// assert!(inferred.exact);
+/// assert_eq!(inferred.encoder(data).encode_to_vec().unwrap(), compressed);
+/// ```
+pub fn infer_settings(bytes: &[u8]) -> Result<InferredSettings, VpkError> {
+    let original = Decoder::for_bytes(bytes).decode_tokens()?;
+    let method = original.header.method;
+    let tree_mode = find_tree_mode(&original)?;
+
+    let candidates = lzss_candidates(&original);
+    let mut closest: Option<InferredSettings> = None;
+    for &backend in BACKENDS {
+        for &settings in &candidates {
+            for lazy in lazy_candidates() {
+                let tokens = Encoder::for_bytes(&original.data)
+                    .method(method)
+                    .with_lzss_settings(settings)
+                    .lzss_backend(backend)
+                    .lazy_matching(lazy)
+                    .encode_to_tokens()?;
+                let mismatch = first_mismatch(&original.tokens, &tokens);
+
+                let closer = match (mismatch, &closest) {
+                    (_, None) | (None, _) => true,
+                    (Some(m), Some(c)) => c.mismatch.map_or(false, |cm| m.index > cm.index),
+                };
+                if closer {
+                    closest = Some(InferredSettings {
+                        method,
+                        settings,
+                        backend,
+                        lazy,
+                        trees: original.trees.clone(),
+                        tree_mode,
+                        mismatch,
+                        exact: false,
+                    });
+                }
+
+                if mismatch.is_none() {
+                    let mut inferred = closest.unwrap();
+                    inferred.exact = inferred.encoder(&original.data).encode_to_vec()? == bytes;
+                    return Ok(inferred);
+                }
+            }
+        }
+    }
+
+    // there is always at least one candidate
+    Ok(closest.unwrap())
+}
+
+/// Find the tree mode that builds the same trees as `original` from its tokens
+fn find_tree_mode(original: &DecodedTokens) -> Result<Option<TreeMode>, VpkError> {
+    let pass = token_pass(original.header.method, original.tokens.iter().copied())?;
+    let offsets = original.trees.offsets.to_string();
+    let lengths = original.trees.lengths.to_string();
+
+    for &mode in &[TreeMode::Nintendo, TreeMode::Huffman] {
+        let maps = EncodedMaps::new(None, None, mode, MAX_CODE_LENGTH, false, &pass)?;
+        if maps.offsets.tree.to_string() == offsets && maps.lengths.tree.to_string() == lengths {
+            return Ok(Some(mode));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The LZSS settings with a window and longest match that fit the copy-backs in `original`.
+/// The default settings are checked first, then the smallest settings that fit.
+fn lzss_candidates(original: &DecodedTokens) -> Vec<LzssSettings> {
+    let method = original.header.method;
+    let copies = original.tokens.iter().filter(|t| t.move_back(method) > 0);
+    let farthest = copies.clone().map(|t| t.move_back(method)).max();
+    let longest = copies.clone().map(Token::len).max();
+    let shortest = copies.map(Token::len).min();
+
+    let default = LzssSettings::default();
+    let needed = |n: Option<usize>| (count_needed_bits(n.unwrap_or(1)) as usize).max(1);
+    let offset_bits = [default.offset_bits.max(needed(farthest)), needed(farthest)];
+    let length_bits = [default.length_bits.max(needed(longest)), needed(longest)];
+    let max_uncoded = match shortest {
+        Some(s) => [
+            default.max_uncoded.min(s.saturating_sub(1)),
+            s.saturating_sub(1),
+        ],
+        None => [default.max_uncoded; 2],
+    };
+
+    let mut candidates = Vec::new();
+    for &offset in &offset_bits {
+        for &length in &length_bits {
+            for &uncoded in &max_uncoded {
+                let settings = LzssSettings::new(offset, length, uncoded);
+                if !candidates.contains(&settings) {
+                    candidates.push(settings);
+                }
+            }
+        }
+    }
+
+    candidates
+}
+
+/// The built-in lazy matching policies, from the most common
+fn lazy_candidates() -> impl Iterator<Item = LazyMatching> {
+    let nintendo = (2..=MAX_LAZY_DEPTH)
+        .filter(|&d| d != LazyMatching::NINTENDO_DEPTH)
+        .map(LazyMatching::Nintendo);
+
+    vec![
+        LazyMatching::default(),
+        LazyMatching::Greedy,
+        LazyMatching::OneStep,
+    ]
+    .into_iter()
+    .chain(nintendo)
+}
+
+fn first_mismatch(expected: &[Token], found: &[Token]) -> Option<TokenMismatch> {
+    let index = expected
+        .iter()
+        .zip(found)
+        .take_while(|(e, f)| e == f)
+        .count();
+    if index == expected.len() && index == found.len() {
+        return None;
+    }
+
+    Some(TokenMismatch {
+        index,
+        expected: expected.get(index).copied(),
+        found: found.get(index).copied(),
+    })
+}
@@ -1,13 +1,18 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     convert::TryInto,
     fmt,
     io::{self, Read, Write},
 };
 
 use slice_deque::SliceDeque;
+use smallvec::SmallVec;
+use std::collections::VecDeque;
 
-use crate::{errors::VpkError, format::VpkMethod};
+use crate::{
+    errors::VpkError,
+    format::{OffsetSample, Token, VpkMethod},
+};
 
 use super::{count_needed_bits, BitSize, Frequency, LzssBackend, TwoSample};
 
@@ -64,14 +69,14 @@ impl LzssSettings {
         }
     }
 
-    const fn window_size(&self) -> usize {
+    pub(super) const fn window_size(&self) -> usize {
         // overflow assert?
         (1 << self.offset_bits) - 1
     }
     /// maximum number of bytes that can be encoded
     /// note that Nintendo's VPK encoder uses the extra `max_uncoded` bits for
     /// encoding a TwoSample vpk file, so you cannot use them here to encode longer matches
-    const fn max_encoded(&self) -> usize {
+    pub(super) const fn max_encoded(&self) -> usize {
         // overflow assert?
         (1 << self.length_bits) - 1
     }
@@ -92,32 +97,26 @@ pub(super) struct LzssPass {
     pub buf: Vec<LzssByte>,
     pub decompressed_size: Option<u32>,
     // for the bit size of copy back size (lzss "length")
-    pub size_bitfreq: HashMap<BitSize, Frequency>,
+    pub size_bitfreq: BTreeMap<BitSize, Frequency>,
     // for the bit size of moveback (lzss "offset" or "distance")
-    pub moveback_bitfreq: HashMap<BitSize, Frequency>,
+    pub moveback_bitfreq: BTreeMap<BitSize, Frequency>,
 }
 
 impl LzssPass {
-    fn new(input_size: usize, settings: &LzssSettings) -> Self {
-        let buf = Vec::with_capacity(input_size);
-        let max_size_bits = count_needed_bits(settings.max_encoded()) as usize;
-        let size_bitfreq = HashMap::with_capacity(max_size_bits);
-        let max_mb_bits = count_needed_bits(settings.window_size()) as usize;
-        let moveback_bitfreq = HashMap::with_capacity(max_mb_bits);
-
+    pub(super) fn new(input_size: usize) -> Self {
         Self {
-            buf,
+            buf: Vec::with_capacity(input_size),
             decompressed_size: None,
-            size_bitfreq,
-            moveback_bitfreq,
+            size_bitfreq: BTreeMap::new(),
+            moveback_bitfreq: BTreeMap::new(),
         }
     }
 
-    fn add_uncoded(&mut self, byte: u8) {
+    pub(super) fn add_uncoded(&mut self, byte: u8) {
         self.buf.push(LzssByte::Uncoded(byte))
     }
 
-    fn add(&mut self, byte: LzssByte) {
+    pub(super) fn add(&mut self, byte: LzssByte) {
         // count new length/size and offset/moveback bitwidths
         match byte {
             LzssByte::Encoded(size, offset) => {
@@ -186,30 +185,57 @@ pub(super) fn compress_rdr<R: Read>(
     settings: LzssSettings,
     method: VpkMethod,
     backend: LzssBackend,
+    policy: &mut dyn LazyPolicy,
     log: &mut Option<&mut dyn Write>,
 ) -> Result<LzssPass, VpkError> {
-    let mut dict = SlidingDict::new(input, &settings)?;
-    let mut compressed = LzssPass::new(dict.total_read, &settings);
+    compress_after(input, 0, settings, method, backend, policy, log)
+}
 
-    let lzss_algo = match backend {
-        LzssBackend::Brute => &NaiveBrute as &dyn MatchFinder,
-        LzssBackend::Kmp => &KmpStandard as &dyn MatchFinder,
-        LzssBackend::KmpAhead => &KmpLookAhead as &dyn MatchFinder,
+/// Compress the data in `input` after the first `history` bytes, which matches can copy from.
+/// `history` can't be larger than the window.
+pub(super) fn compress_after<R: Read>(
+    input: R,
+    history: usize,
+    settings: LzssSettings,
+    method: VpkMethod,
+    backend: LzssBackend,
+    policy: &mut dyn LazyPolicy,
+    log: &mut Option<&mut dyn Write>,
+) -> Result<LzssPass, VpkError> {
+    let depth = policy.depth();
+    if depth == 0 || depth > LazyMatching::MAX_DEPTH {
+        return Err(VpkError::InvalidLazyDepth(depth));
+    }
+    let mut dict = SlidingDict::new(input, &settings, depth)?;
+    if history > 0 {
+        dict.advance_by(history)?;
+    }
+    let mut compressed = LzssPass::new(dict.total_read);
+
+    let mut lzss_algo = match backend {
+        LzssBackend::Brute => Box::new(NaiveBrute) as Box<dyn MatchFinder>,
+        LzssBackend::Kmp => Box::new(KmpStandard),
+        LzssBackend::KmpAhead => Box::new(KmpLookAhead),
+        LzssBackend::HashChain { depth } => Box::new(HashChain::new(depth)),
+        LzssBackend::BinaryTree => Box::new(BinaryTree::new(&settings)),
     };
 
     while dict.remaining() > 0 {
-        let bytes_matched = match look_for_nearby_best_match(&dict, &settings, log, lzss_algo) {
-            LookAhead::Match(skipped, m) => add_match(m, skipped, method, &mut compressed, log),
-            LookAhead::Uncoded => {
-                compressed.add_uncoded(dict.next_uncoded_byte().unwrap());
-                1
-            }
-        };
+        let bytes_matched =
+            match look_for_nearby_best_match(&dict, &settings, log, &mut *lzss_algo, policy)? {
+                LookAhead::Match(skipped, m) => {
+                    add_match(m, skipped, method, &mut compressed, log)?
+                }
+                LookAhead::Uncoded => {
+                    compressed.add_uncoded(dict.next_uncoded_byte().unwrap());
+                    1
+                }
+            };
 
         dict.advance_by(bytes_matched)?;
     }
 
-    compressed.decompressed_size = Some(dict.total_read.try_into()?);
+    compressed.decompressed_size = Some((dict.total_read - history).try_into()?);
 
     /*
     if let Some(wtr) = log.as_mut() {
@@ -226,11 +252,11 @@ fn add_match(
     method: VpkMethod,
     output: &mut LzssPass,
     log: &mut Option<&mut dyn Write>,
-) -> usize {
+) -> Result<usize, VpkError> {
     let total_bytes = mat.size + skipped.len();
 
     if let Some(wtr) = log {
-        writeln!(wtr, "adding match: {:?} then {:?}", skipped, &mat).unwrap();
+        writeln!(wtr, "adding match: {:?} then {:?}", skipped, &mat).map_err(VpkError::Log)?;
     }
 
     for &byte in skipped {
@@ -244,7 +270,162 @@ fn add_match(
 
     output.add(encoded);
 
-    total_bytes
+    Ok(total_bytes)
+}
+
+/// How the encoder decides between using a match, or checking the following
+/// bytes for a longer match
+///
+/// When a longer match is used, the bytes before it are output uncoded.
+/// Use [`Encoder::lazy_matching`](crate::Encoder::lazy_matching) to choose a built-in policy,
+/// or implement [`LazyPolicy`] for a custom policy.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum LazyMatching {
+    /// Check up to `depth` bytes (including the current byte) for a match, and keep
+    /// checking while each match is longer than the last one. Use the last, longest match.
+    ///
+    /// This is the default, and a depth of [`NINTENDO_DEPTH`](LazyMatching::NINTENDO_DEPTH)
+    /// matches the files in *Super Smash Bros. 64*. The depth must be between 1 and
+    /// [`MAX_DEPTH`](LazyMatching::MAX_DEPTH).
+    Nintendo(usize),
+    /// Always use the match at the current byte
+    Greedy,
+    /// zlib style lazy matching: if the match at the next byte is longer than
+    /// the match at the current byte, output the current byte uncoded and
+    /// check again from the next byte
+    OneStep,
+}
+
+impl LazyMatching {
+    /// The maximum depth needed to match all of the `vpk0` files in *Super Smash Bros. 64*
+    pub const NINTENDO_DEPTH: usize = 10;
+    /// The largest depth that a [`LazyPolicy`] can check
+    pub const MAX_DEPTH: usize = 4096;
+}
+
+impl Default for LazyMatching {
+    fn default() -> Self {
+        Self::Nintendo(Self::NINTENDO_DEPTH)
+    }
+}
+
+impl LazyPolicy for LazyMatching {
+    fn depth(&self) -> usize {
+        match *self {
+            Self::Nintendo(depth) => depth,
+            Self::Greedy => 1,
+            Self::OneStep => 2,
+        }
+    }
+
+    fn choose(&mut self, matches: &mut MatchCandidates<'_>) -> Option<usize> {
+        match *self {
+            Self::Nintendo(depth) => {
+                let mut best: Option<(usize, usize)> = None;
+                for offset in 0..depth {
+                    match matches.length(offset) {
+                        Some(length) if best.map_or(true, |(_, b)| length > b) => {
+                            best = Some((offset, length))
+                        }
+                        _ => break,
+                    }
+                }
+
+                best.map(|(offset, _)| offset)
+            }
+            Self::Greedy => matches.length(0).map(|_| 0),
+            Self::OneStep => {
+                let current = matches.length(0)?;
+                match matches.length(1) {
+                    Some(next) if next > current => None,
+                    _ => Some(0),
+                }
+            }
+        }
+    }
+}
+
+/// A custom policy for choosing which match to use when encoding
+///
+/// ```
+/// # use vpk0::{Encoder, LazyPolicy, MatchCandidates};
+/// /// Use the longest match in the next four bytes
+/// struct Longest;
+///
+/// impl LazyPolicy for Longest {
+///     fn depth(&self) -> usize {
+///         4
+///     }
+///
+///     fn choose(&mut self, matches: &mut MatchCandidates<'_>) -> Option<usize> {
+///         (0..matches.len())
+///             .filter_map(|offset| matches.length(offset).map(|len| (len, offset)))
+///             .max_by_key(|&(len, offset)| (len, std::cmp::Reverse(offset)))
+///             .map(|(_, offset)| offset)
+///     }
+/// }
+///
+/// let mut policy = Longest;
+/// let compressed = Encoder::for_bytes(b"sam I am I am sam")
+///     .lazy_policy(&mut policy)
+///     .encode_to_vec();
+/// ```
+pub trait LazyPolicy {
+    /// The number of bytes, starting with the current byte, that this policy can check for matches.
+    /// This is at least one and at most [`LazyMatching::MAX_DEPTH`], or encoding fails with
+    /// [`VpkError::InvalidLazyDepth`].
+    fn depth(&self) -> usize;
+
+    /// Choose the match to use from `matches`, and return how many bytes ahead of the
+    /// current byte it starts. The bytes before the match are output uncoded.
+    /// If `None` is returned, or there isn't a match at the chosen offset,
+    /// only the current byte is output uncoded.
+    fn choose(&mut self, matches: &mut MatchCandidates<'_>) -> Option<usize>;
+}
+
+/// The matches that a [`LazyPolicy`] can choose from
+///
+/// Matches are searched for when they are first requested.
+pub struct MatchCandidates<'m> {
+    find: &'m mut dyn FnMut(usize) -> Option<MoveBack>,
+    found: SmallVec<[Option<Option<MoveBack>>; 16]>,
+}
+
+impl<'m> MatchCandidates<'m> {
+    fn new(len: usize, find: &'m mut dyn FnMut(usize) -> Option<MoveBack>) -> Self {
+        Self {
+            find,
+            found: SmallVec::from_elem(None, len),
+        }
+    }
+
+    /// The number of bytes that can be checked for matches. This is
+    /// [`LazyPolicy::depth`] unless the end of the input is closer.
+    pub fn len(&self) -> usize {
+        self.found.len()
+    }
+
+    /// Check if there are no bytes to be checked for matches
+    pub fn is_empty(&self) -> bool {
+        self.found.is_empty()
+    }
+
+    /// Get the length of the match that starts `offset` bytes ahead, or `None` if there
+    /// isn't a match longer than [`LzssSettings::max_uncoded`]
+    pub fn length(&mut self, offset: usize) -> Option<usize> {
+        self.get(offset).map(|m| m.size)
+    }
+
+    /// Get how far back the match that starts `offset` bytes ahead copies from
+    pub fn move_back(&mut self, offset: usize) -> Option<usize> {
+        self.get(offset).map(|m| m.moveback)
+    }
+
+    fn get(&mut self, offset: usize) -> Option<MoveBack> {
+        let find = &mut self.find;
+        let slot = self.found.get_mut(offset)?;
+        *slot.get_or_insert_with(|| find(offset))
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -273,6 +454,39 @@ pub(super) enum LzssByte {
 }
 
 impl LzssByte {
+    pub(super) fn to_token(&self) -> Token {
+        match *self {
+            Self::Uncoded(byte) => Token::Literal(byte),
+            Self::Encoded(length, offset) => Token::Match { offset, length },
+            Self::EncTwoSample(length, TwoSample::One(v)) => Token::TwoSampleMatch {
+                offset: OffsetSample::One(v),
+                length,
+            },
+            Self::EncTwoSample(length, TwoSample::Two { first, second }) => Token::TwoSampleMatch {
+                offset: OffsetSample::Two(first, second),
+                length,
+            },
+        }
+    }
+
+    /// Convert `token`, or return `None` if it is a copy-back for the other `method`
+    pub(super) fn from_token(token: Token, method: VpkMethod) -> Option<Self> {
+        match (token, method) {
+            (Token::Literal(byte), _) => Some(Self::Uncoded(byte)),
+            (Token::Match { offset, length }, VpkMethod::OneSample) => {
+                Some(Self::Encoded(length, offset))
+            }
+            (Token::TwoSampleMatch { offset, length }, VpkMethod::TwoSample) => {
+                let sample = match offset {
+                    OffsetSample::One(v) => TwoSample::One(v),
+                    OffsetSample::Two(first, second) => TwoSample::Two { first, second },
+                };
+                Some(Self::EncTwoSample(length, sample))
+            }
+            _ => None,
+        }
+    }
+
     // total number of bytes this byte encodes from the uncoded input
     fn size(&self) -> usize {
         match self {
@@ -283,8 +497,6 @@ impl LzssByte {
     }
 }
 
-const MAX_AHEAD_CHECK: usize = 10;
-
 #[derive(Debug)]
 struct SlidingDict<R> {
     /// size of the look-behind dictionary window
@@ -295,8 +507,12 @@ struct SlidingDict<R> {
     buf_size: usize,
     /// max possible size of lookahead + peek bytes
     //max_ahead: usize,
+    /// number of bytes past the lookahead that can be looked at
+    peek: usize,
     /// current position in `buf` for start of lookahead
     csr: usize,
+    /// position in the input for the start of the lookahead
+    position: usize,
     buf: SliceDeque<u8>,
     rdr: R,
     /// is there any more data to be read from `rdr`
@@ -306,19 +522,17 @@ struct SlidingDict<R> {
 }
 
 impl<R: Read> SlidingDict<R> {
-    const MAX_PEEK: usize = MAX_AHEAD_CHECK;
-
-    fn new(mut rdr: R, settings: &LzssSettings) -> io::Result<Self> {
+    fn new(mut rdr: R, settings: &LzssSettings, peek: usize) -> io::Result<Self> {
         // total size of the buffer is the size of the lookback window
         // plus the size of the lookahead
         let window = settings.window_size();
         let lookahead = settings.max_encoded();
         let buf_size = window + lookahead;
-        let max_ahead = lookahead + Self::MAX_PEEK;
+        let max_ahead = lookahead + peek;
 
         // at the start, everything is in the lookahead
         let csr = 0;
-        let mut buf = SliceDeque::with_capacity(buf_size + Self::MAX_PEEK);
+        let mut buf = SliceDeque::with_capacity(buf_size + peek);
         buf.resize(max_ahead, 0);
         // TODO: read another way here? like the copied read_exact implementation?
         let total_read = rdr.read(&mut buf[csr..max_ahead])?;
@@ -338,7 +552,9 @@ impl<R: Read> SlidingDict<R> {
             //lookahead,
             buf_size,
             //max_ahead,
+            peek,
             csr,
+            position: 0,
             buf,
             rdr,
             more_to_read,
@@ -353,8 +569,8 @@ impl<R: Read> SlidingDict<R> {
 
     /// get the (behind, ahead, full) buffers offset by `n` for performing ahead matches
     /// without reading new data
-    fn offset_csr(&self, n: usize) -> Bufs {
-        assert!(n <= Self::MAX_PEEK);
+    fn offset_csr(&self, n: usize) -> Bufs<'_> {
+        assert!(n <= self.peek);
         let offset_end = self.buf.len().min(self.buf_size + n);
         let w_end = self.csr + n;
         let w_start = w_end.saturating_sub(self.window);
@@ -367,6 +583,8 @@ impl<R: Read> SlidingDict<R> {
             ahead,
             behind,
             full,
+            position: self.position + n,
+            offset: n,
         }
     }
 
@@ -398,6 +616,7 @@ impl<R: Read> SlidingDict<R> {
         }
         // advance the cursor
         self.csr = new_csr;
+        self.position += n;
         // fill the back fo the ring buffer with `n` new bytes from `rdr`
         if self.more_to_read {
             let len = self.buf.len();
@@ -444,11 +663,15 @@ struct Bufs<'a> {
     ahead: &'a [u8],
     behind: &'a [u8],
     full: &'a [u8],
+    /// position of the start of `ahead` in the input
+    position: usize,
+    /// how many bytes `position` is past the current position of the encoder
+    offset: usize,
 }
 
 trait MatchFinder {
     fn find(
-        &self,
+        &mut self,
         bufs: Bufs,
         settings: &LzssSettings,
         log: &mut Option<&mut dyn Write>,
@@ -459,7 +682,7 @@ trait MatchFinder {
 struct KmpStandard;
 impl MatchFinder for KmpStandard {
     fn find(
-        &self,
+        &mut self,
         bufs: Bufs,
         settings: &LzssSettings,
         _log: &mut Option<&mut dyn Write>,
@@ -472,7 +695,7 @@ impl MatchFinder for KmpStandard {
 struct KmpLookAhead;
 impl MatchFinder for KmpLookAhead {
     fn find(
-        &self,
+        &mut self,
         bufs: Bufs,
         settings: &LzssSettings,
         _log: &mut Option<&mut dyn Write>,
@@ -485,7 +708,7 @@ impl MatchFinder for KmpLookAhead {
 struct NaiveBrute;
 impl MatchFinder for NaiveBrute {
     fn find(
-        &self,
+        &mut self,
         bufs: Bufs,
         settings: &LzssSettings,
         _log: &mut Option<&mut dyn Write>,
@@ -494,48 +717,425 @@ impl MatchFinder for NaiveBrute {
     }
 }
 
-/// Check for the "best" match in behind window of `dict` by using `method`
-/// This will keep look at the next offset for matches until either
-/// (a) no match is found, or
-/// (b) the found match is smaller than the previous match.
-/// "Best" is, I assume, highly debateable, but this seems to match what Nintendo did.
+/// Find matches by following a chain of the earlier positions in the input that start with
+/// the same bytes. Only the closest `depth` positions in the chain are checked.
+/// Like `brute_find_match`, the longest match is used, and then the closest one.
+#[derive(Debug)]
+struct HashChain {
+    depth: usize,
+    /// the latest position (plus one) with each hash, or zero if there isn't one
+    head: Box<[u32]>,
+    /// the previous position (plus one) with the same hash as each position in the input
+    prev: Vec<u32>,
+}
+
+impl HashChain {
+    const HASH_BITS: u32 = 15;
+    /// number of bytes that are hashed, unless the minimum match is smaller
+    const KEY_LEN: usize = 3;
+
+    fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            head: vec![0; 1 << Self::HASH_BITS].into_boxed_slice(),
+            prev: Vec::new(),
+        }
+    }
+
+    fn hash(key: &[u8]) -> usize {
+        let hash = key.iter().fold(0, |h, &b| (h << 5) ^ b as usize);
+        hash & ((1 << Self::HASH_BITS) - 1)
+    }
+
+    /// Add every position in the window before `position` to the chains.
+    /// `full` starts at `start` and ends at `end` in the input.
+    fn insert_until(&mut self, full: &[u8], start: usize, position: usize, key_len: usize) {
+        let end = start + full.len();
+        let last = (end + 1).saturating_sub(key_len).min(position);
+
+        for pos in self.prev.len().max(start)..last {
+            let key = &full[pos - start..pos - start + key_len];
+            let head = &mut self.head[Self::hash(key)];
+
+            self.prev.resize(pos, 0);
+            self.prev.push(*head);
+            *head = pos as u32 + 1;
+        }
+    }
+}
+
+impl MatchFinder for HashChain {
+    fn find(
+        &mut self,
+        bufs: Bufs,
+        settings: &LzssSettings,
+        _log: &mut Option<&mut dyn Write>,
+    ) -> Option<MoveBack> {
+        let Bufs {
+            ahead,
+            behind,
+            full,
+            position,
+            ..
+        } = bufs;
+        let start = position - behind.len();
+        let longest_match = settings.max_encoded();
+        let shortest_match = settings.max_uncoded + 1;
+        let key_len = shortest_match.min(Self::KEY_LEN);
+
+        self.insert_until(full, start, position, key_len);
+        if ahead.len() < shortest_match {
+            return None;
+        }
+
+        let mut best: Option<MoveBack> = None;
+        let mut next = self.head[Self::hash(&ahead[..key_len])];
+        let mut checked = 0;
+        while next != 0 && checked < self.depth {
+            let pos = next as usize - 1;
+            next = self.prev[pos];
+
+            // positions at or after `position` were added while looking ahead
+            if pos >= position {
+                continue;
+            }
+            if pos < start {
+                break;
+            }
+            checked += 1;
+
+            let length = full[pos - start..]
+                .iter()
+                .zip(ahead)
+                .take_while(|(s, d)| s == d)
+                .count()
+                .min(longest_match);
+
+            // the chain goes from closest to farthest, so only replace with longer matches
+            if length >= shortest_match && best.map_or(true, |b| length > b.size) {
+                best = Some(MoveBack::new(length, position - pos));
+                if length == longest_match.min(ahead.len()) {
+                    break;
+                }
+            }
+        }
+
+        best
+    }
+}
+
+/// Find matches with a binary search tree of every earlier position in the window,
+/// sorted by the bytes that start at each position (like LZMA's binary tree match finder).
+///
+/// The newest position is always the root of the tree, and every node is newer than its
+/// children. So, the search for the current position visits the closest earlier position
+/// that matches each length, and this gives the same matches as `brute_find_match`.
+/// Every position has to be added to the tree in order, so the input and the match for
+/// each position are stored by the finder.
+#[derive(Debug)]
+struct BinaryTree {
+    /// the input bytes seen so far
+    data: Vec<u8>,
+    tree: PositionTree,
+    /// the next position to add to the tree
+    next: usize,
+    /// the match for each position that was added, but not yet used
+    found: VecDeque<(usize, Option<MoveBack>)>,
+}
+
+impl BinaryTree {
+    fn new(settings: &LzssSettings) -> Self {
+        Self {
+            data: Vec::new(),
+            tree: PositionTree::new(settings.window_size(), settings),
+            next: 0,
+            found: VecDeque::new(),
+        }
+    }
+}
+
+/// The binary search tree of a [`BinaryTree`] finder: the positions in a window,
+/// sorted by the bytes that start at each position
+#[derive(Debug)]
+struct PositionTree {
+    /// the (smaller, larger) children of each position in the window,
+    /// indexed by position modulo the window size
+    children: Box<[(u32, u32)]>,
+    root: u32,
+    window: usize,
+    longest_match: usize,
+    shortest_match: usize,
+}
+
+impl PositionTree {
+    const NONE: u32 = u32::MAX;
+
+    fn new(window: usize, settings: &LzssSettings) -> Self {
+        Self {
+            children: vec![(Self::NONE, Self::NONE); window + 1].into_boxed_slice(),
+            root: Self::NONE,
+            window,
+            longest_match: settings.max_encoded(),
+            shortest_match: settings.max_uncoded + 1,
+        }
+    }
+
+    /// Add `cur` as the new root of the tree, and pass each match for it to `found`.
+    /// The matches are from shortest to longest, and each one is the closest match of its length.
+    fn insert<F: FnMut(MoveBack)>(&mut self, data: &[u8], cur: usize, mut found: F) {
+        let shortest_match = self.shortest_match;
+        let children = &mut self.children;
+        let cycle = children.len();
+        let limit = self.longest_match.min(data.len() - cur);
+
+        // the last child slot of a smaller (`lower`) or larger (`upper`) position
+        // that the next smaller or larger position will be attached to
+        let mut lower = (cur % cycle, false);
+        let mut upper = (cur % cycle, true);
+        let (mut lower_len, mut upper_len) = (0, 0);
+        let mut best_len = 0;
+        let mut node = self.root;
+
+        loop {
+            let pos = node as usize;
+            if node == Self::NONE || cur - pos > self.window {
+                set_child(children, lower, Self::NONE);
+                set_child(children, upper, Self::NONE);
+                break;
+            }
+
+            // everything between the lower and upper bounds shares their common prefix
+            let mut len = lower_len.min(upper_len);
+            len += data[pos + len..]
+                .iter()
+                .zip(&data[cur + len..cur + limit])
+                .take_while(|(s, d)| s == d)
+                .count();
+
+            if len > best_len {
+                best_len = len;
+                if len >= shortest_match {
+                    found(MoveBack::new(len, cur - pos));
+                }
+            }
+
+            if len == limit {
+                // `cur` replaces the older `pos`, which has the same bytes
+                let (smaller, larger) = children[pos % cycle];
+                set_child(children, lower, smaller);
+                set_child(children, upper, larger);
+                break;
+            }
+
+            if data[pos + len] < data[cur + len] {
+                set_child(children, lower, node);
+                lower = (pos % cycle, true);
+                lower_len = len;
+                node = children[pos % cycle].1;
+            } else {
+                set_child(children, upper, node);
+                upper = (pos % cycle, false);
+                upper_len = len;
+                node = children[pos % cycle].0;
+            }
+        }
+
+        self.root = cur as u32;
+    }
+
+    /// Pass each match for a later position `cur` to `found` like [`insert`](Self::insert),
+    /// without adding it to the tree. Only matches up to `farthest` bytes back are used,
+    /// which can be more than the window if the newest position is before `cur - 1`.
+    fn search<F: FnMut(MoveBack)>(&self, data: &[u8], cur: usize, farthest: usize, mut found: F) {
+        let cycle = self.children.len();
+        let limit = self.longest_match.min(data.len() - cur);
+
+        let (mut lower_len, mut upper_len) = (0, 0);
+        let mut best_len = 0;
+        let mut node = self.root;
+
+        while node != Self::NONE && cur - node as usize <= farthest {
+            let pos = node as usize;
+            let mut len = lower_len.min(upper_len);
+            len += data[pos + len..]
+                .iter()
+                .zip(&data[cur + len..cur + limit])
+                .take_while(|(s, d)| s == d)
+                .count();
+
+            if len > best_len {
+                best_len = len;
+                if len >= self.shortest_match {
+                    found(MoveBack::new(len, cur - pos));
+                }
+            }
+
+            if len == limit {
+                break;
+            }
+
+            if data[pos + len] < data[cur + len] {
+                lower_len = len;
+                node = self.children[pos % cycle].1;
+            } else {
+                upper_len = len;
+                node = self.children[pos % cycle].0;
+            }
+        }
+    }
+}
+
+/// Every match for each position in an input, for finding the best parse
+#[derive(Debug)]
+pub(super) struct AllMatches {
+    /// the index in `matches` of the first match for each position, plus the end
+    starts: Vec<usize>,
+    /// (length, move back) for each position from shortest to longest: the closest match
+    /// of each length, and the longest match for each bit size of the move back
+    matches: Vec<(usize, usize)>,
+}
+
+impl AllMatches {
+    pub(super) fn new(data: &[u8], settings: &LzssSettings) -> Self {
+        let mut tree = PositionTree::new(settings.window_size(), settings);
+        // a tree for each bit size of the move back, with only the positions that far back.
+        // A farther match can have a cheaper offset code than the closest match
+        let mut sized: Vec<(usize, PositionTree)> = (1..=settings.offset_bits)
+            .map(|bits| {
+                let nearest = 1 << (bits - 1);
+                let farthest = (1 << bits) - 1;
+                (nearest, PositionTree::new(farthest - nearest, settings))
+            })
+            .collect();
+
+        let mut starts = Vec::with_capacity(data.len() + 1);
+        let mut matches = Vec::new();
+        for pos in 0..data.len() {
+            let first = matches.len();
+            starts.push(first);
+            tree.insert(data, pos, |m| matches.push((m.size, m.moveback)));
+
+            for (nearest, sized) in &mut sized {
+                let newest = match pos.checked_sub(*nearest) {
+                    Some(newest) => newest,
+                    None => break,
+                };
+                sized.insert(data, newest, |_| ());
+
+                let mut longest = None;
+                sized.search(data, pos, *nearest + sized.window, |m| {
+                    longest = Some((m.size, m.moveback))
+                });
+                if let Some(m) = longest {
+                    if !matches[first..].contains(&m) {
+                        matches.push(m);
+                    }
+                }
+            }
+            matches[first..].sort_by_key(|&(len, _)| len);
+        }
+        starts.push(matches.len());
+
+        Self { starts, matches }
+    }
+
+    pub(super) fn at(&self, pos: usize) -> &[(usize, usize)] {
+        &self.matches[self.starts[pos]..self.starts[pos + 1]]
+    }
+}
+
+/// Set the smaller or `larger` child of the position at `idx`
+fn set_child(children: &mut [(u32, u32)], (idx, larger): (usize, bool), child: u32) {
+    let slot = &mut children[idx];
+    if larger {
+        slot.1 = child;
+    } else {
+        slot.0 = child;
+    }
+}
+
+impl MatchFinder for BinaryTree {
+    fn find(
+        &mut self,
+        bufs: Bufs,
+        _settings: &LzssSettings,
+        _log: &mut Option<&mut dyn Write>,
+    ) -> Option<MoveBack> {
+        let Bufs {
+            full,
+            behind,
+            position,
+            offset,
+            ..
+        } = bufs;
+        let start = position - behind.len();
+        let seen = self.data.len() - start;
+        if seen < full.len() {
+            self.data.extend_from_slice(&full[seen..]);
+        }
+
+        // matches before the current position of the encoder aren't needed anymore
+        while matches!(self.found.front(), Some(&(pos, _)) if pos < position - offset) {
+            self.found.pop_front();
+        }
+
+        while self.next <= position {
+            let mut best = None;
+            self.tree.insert(&self.data, self.next, |m| best = Some(m));
+            self.found.push_back((self.next, best));
+            self.next += 1;
+        }
+
+        self.found
+            .iter()
+            .find(|&&(pos, _)| pos == position)
+            .and_then(|&(_, m)| m)
+    }
+}
+
+/// Check for the "best" match in behind window of `dict` by using `lzss_algo`
+/// The matches at the current and following offsets are given to `policy` to choose from.
 fn look_for_nearby_best_match<'a, R>(
     dict: &'a SlidingDict<R>,
     settings: &LzssSettings,
     log: &mut Option<&mut dyn Write>,
-    lzss_algo: &dyn MatchFinder,
-) -> LookAhead<'a>
+    lzss_algo: &mut dyn MatchFinder,
+    policy: &mut dyn LazyPolicy,
+) -> Result<LookAhead<'a>, VpkError>
 where
     R: Read,
 {
-    let m = dict
-        .ahead()
-        .iter()
-        .enumerate()
-        .take(MAX_AHEAD_CHECK)
-        .scan(0, |best, (offset, _byte)| {
+    let len = dict.remaining().min(dict.peek);
+    let mut log_err = None;
+    let m = {
+        let mut find = |offset| {
             if let Some(wtr) = log.as_mut() {
-                writeln!(wtr, "\tlooking at offset {}", offset).unwrap();
+                if let Err(e) = writeln!(wtr, "\tlooking at offset {}", offset) {
+                    log_err.get_or_insert(e);
+                }
             }
             let bufs = dict.offset_csr(offset);
 
             lzss_algo
                 .find(bufs, settings, log)
                 .filter(|m| m.size > settings.max_uncoded)
-                .filter(|m| m.size > *best)
-                .map(|m| {
-                    *best = m.size;
-                    (offset, m)
-                })
-        })
-        .last()
-        .map(|(o, m)| LookAhead::Match(&dict.ahead()[..o], m));
+        };
+        let mut matches = MatchCandidates::new(len, &mut find);
+
+        policy
+            .choose(&mut matches)
+            .and_then(|o| matches.get(o).map(|m| (o, m)))
+    }
+    .map(|(o, m)| LookAhead::Match(&dict.ahead()[..o], m));
 
+    if let Some(e) = log_err {
+        return Err(VpkError::Log(e));
+    }
     if let Some(wtr) = log.as_mut() {
-        writeln!(wtr, "\tfound {:?}", m).unwrap();
+        writeln!(wtr, "\tfound {:?}", m).map_err(VpkError::Log)?;
     }
 
-    m.unwrap_or(LookAhead::Uncoded)
+    Ok(m.unwrap_or(LookAhead::Uncoded))
 }
 
 /// Naive search to find `bufs.ahead` in `buf.behind`.
@@ -546,6 +1146,7 @@ fn brute_find_match(bufs: Bufs, settings: &LzssSettings) -> Option<MoveBack> {
         behind,
         ahead,
         full,
+        ..
     } = bufs;
     let window_size = behind.len();
     let longest_match = settings.max_encoded();
@@ -579,6 +1180,7 @@ fn find_kmp(bufs: Bufs, max: usize, check_rl: bool) -> Option<MoveBack> {
         ahead,
         behind,
         full,
+        ..
     } = bufs;
     let lps = compute_lps(ahead);
     let window_size = behind.len();
@@ -664,3 +1266,161 @@ fn compute_lps(pattern: &[u8]) -> Box<[usize]> {
 
     lps.into()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CORPUS: &[&[u8]] = &[
+        include_bytes!("../../tests/method0-orig.bin"),
+        include_bytes!("../../tests/method1-orig.bin"),
+        include_bytes!("../../tests/small-logo.png"),
+        b"abcdefgh12345",
+        b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+        b"",
+    ];
+
+    #[test]
+    fn binary_tree_matches_brute_corpus() {
+        for input in CORPUS {
+            check_same_as_brute(input);
+        }
+    }
+
+    #[test]
+    fn binary_tree_matches_brute_random() {
+        let mut rng = XorShift(0x5eed_1234_abcd_0001);
+
+        for &alphabet in &[2, 4, 16, 256] {
+            for _ in 0..4 {
+                let len = 500 + rng.next() as usize % 3000;
+                let mut input: Vec<u8> = Vec::with_capacity(len);
+                while input.len() < len {
+                    // mix random bytes with copies of earlier data and runs
+                    match rng.next() % 4 {
+                        0 if input.len() > 8 => {
+                            let start = rng.next() as usize % input.len();
+                            let end = input.len().min(start + rng.next() as usize % 300);
+                            for i in start..end {
+                                let byte = input[i];
+                                input.push(byte);
+                            }
+                        }
+                        1 => {
+                            let byte = (rng.next() % alphabet) as u8;
+                            let run = rng.next() as usize % 40;
+                            input.extend(std::iter::repeat(byte).take(run));
+                        }
+                        _ => input.push((rng.next() % alphabet) as u8),
+                    }
+                }
+
+                check_same_as_brute(&input);
+            }
+        }
+    }
+
+    #[test]
+    fn all_matches_has_longest_of_each_size() {
+        let mut rng = XorShift(0x5eed_1234_abcd_0002);
+        let mut inputs: Vec<Vec<u8>> = CORPUS.iter().map(|input| input.to_vec()).collect();
+        for &alphabet in &[2, 4, 16] {
+            let len = 500 + rng.next() as usize % 1500;
+            inputs.push((0..len).map(|_| (rng.next() % alphabet) as u8).collect());
+        }
+
+        for settings in &[LzssSettings::new(8, 4, 2), LzssSettings::new(10, 5, 1)] {
+            for input in &inputs {
+                let all = AllMatches::new(input, settings);
+                for pos in 0..input.len() {
+                    let found = all.at(pos);
+                    let limit = settings.max_encoded().min(input.len() - pos);
+                    let length = |moveback: usize| {
+                        input[pos - moveback..]
+                            .iter()
+                            .zip(&input[pos..pos + limit])
+                            .take_while(|(s, d)| s == d)
+                            .count()
+                    };
+
+                    // the longest match for each bit size, from the closest move back
+                    let mut longest = vec![(0, 0); settings.offset_bits + 1];
+                    for moveback in 1..=pos.min(settings.window_size()) {
+                        let len = length(moveback);
+                        let bits = count_needed_bits(moveback) as usize;
+                        if len > longest[bits].0 {
+                            longest[bits] = (len, moveback);
+                        }
+                    }
+
+                    assert!(found.windows(2).all(|w| w[0].0 <= w[1].0));
+                    for &(len, moveback) in found {
+                        assert!(length(moveback) >= len, "{:?} at {}", (len, moveback), pos);
+                    }
+                    for &(len, moveback) in &longest {
+                        if len > settings.max_uncoded {
+                            assert!(
+                                found.contains(&(len, moveback)),
+                                "{:?} missing at {}: {:?}",
+                                (len, moveback),
+                                pos,
+                                found
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check that the binary tree and look ahead KMP backends find the same matches as brute force
+    fn check_same_as_brute(input: &[u8]) {
+        let settings = [
+            LzssSettings::default(),
+            LzssSettings::new(8, 4, 2),
+            LzssSettings::new(6, 8, 0),
+            LzssSettings::new(10, 3, 1),
+        ];
+        let policies = [
+            LazyMatching::default(),
+            LazyMatching::Greedy,
+            LazyMatching::OneStep,
+        ];
+
+        for &settings in &settings {
+            for &policy in &policies {
+                for &method in &[VpkMethod::OneSample, VpkMethod::TwoSample] {
+                    let pass = |backend| {
+                        let mut policy = policy;
+                        compress_rdr(input, settings, method, backend, &mut policy, &mut None)
+                            .unwrap()
+                    };
+
+                    let brute = pass(LzssBackend::Brute);
+                    for &backend in &[LzssBackend::BinaryTree, LzssBackend::KmpAhead] {
+                        assert!(
+                            brute.buf == pass(backend).buf,
+                            "different tokens from {:?} for {} bytes with {:?}, {:?}, {:?}",
+                            backend,
+                            input.len(),
+                            settings,
+                            policy,
+                            method
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Choose the LZSS literals and matches that give a small output,
+//! by finding a cheap parse with the bit costs of the current Huffman trees,
+//! then rebuilding the trees and parsing again until the output stops shrinking.
+//!
+//! The trees and the parse depend on each other, so this is a heuristic: the result
+//! is not guaranteed to be the smallest possible file.
+
+use super::{
+    count_needed_bits,
+    huffman::EncodedMaps,
+    lzss::{AllMatches, LzssByte, LzssPass, LzssSettings},
+    write_file, TwoSample,
+};
+use crate::{errors::VpkError, format::VpkMethod};
+use std::{convert::TryInto, io::Write};
+
+/// The most times the input is parsed again with new trees
+const MAX_PASSES: usize = 16;
+
+/// Bits for the encoded/uncoded flag plus the uncoded byte
+const LITERAL_COST: u64 = 1 + 8;
+
+/// Improve `initial` and its trees by parsing `data[start..]` again until the encoded size
+/// converges. Matches can copy from `data[..start]`.
+/// `build` turns a parse of `data[start..]` into the full parse, and creates its Huffman trees.
+pub(super) fn optimize<F>(
+    data: &[u8],
+    start: usize,
+    settings: LzssSettings,
+    method: VpkMethod,
+    initial: (LzssPass, EncodedMaps),
+    build: F,
+    log: &mut Option<&mut dyn Write>,
+) -> Result<(LzssPass, EncodedMaps), VpkError>
+where
+    F: Fn(LzssPass) -> Result<(LzssPass, EncodedMaps), VpkError>,
+{
+    let matches = AllMatches::new(data, &settings);
+
+    let (mut best, mut best_maps) = initial;
+    let mut best_size = encoded_size(method, &best, &best_maps)?;
+    if let Some(wtr) = log.as_mut() {
+        writeln!(wtr, "Optimal parse: initial size {} bytes", best_size).map_err(VpkError::Log)?;
+    }
+
+    for pass in 1..=MAX_PASSES {
+        let costs = Costs::new(method, &best_maps);
+        let parsed = parse(data, start, &settings, method, &matches, &costs)?;
+        let (parsed, maps) = build(parsed)?;
+        let size = encoded_size(method, &parsed, &maps)?;
+        if let Some(wtr) = log.as_mut() {
+            writeln!(wtr, "Optimal parse: pass {} size {} bytes", pass, size)
+                .map_err(VpkError::Log)?;
+        }
+
+        if size >= best_size {
+            break;
+        }
+        best = parsed;
+        best_maps = maps;
+        best_size = size;
+    }
+
+    Ok((best, best_maps))
+}
+
+/// The number of bytes in the `vpk0` file for `pass` encoded with `maps`
+fn encoded_size(method: VpkMethod, pass: &LzssPass, maps: &EncodedMaps) -> Result<usize, VpkError> {
+    let mut out = Vec::new();
+    write_file(&mut out, method, pass, maps)?;
+    Ok(out.len())
+}
+
+/// The bit costs for writing offsets and lengths with a set of trees
+struct Costs {
+    method: VpkMethod,
+    offsets: [Option<u64>; 65],
+    lengths: [Option<u64>; 65],
+}
+
+impl Costs {
+    fn new(method: VpkMethod, maps: &EncodedMaps) -> Self {
+        Self {
+            method,
+            offsets: maps.offsets.bit_costs(),
+            lengths: maps.lengths.bit_costs(),
+        }
+    }
+
+    fn value(costs: &[Option<u64>; 65], val: usize) -> Option<u64> {
+        costs[count_needed_bits(val) as usize]
+    }
+
+    fn offset(&self, moveback: usize) -> Option<u64> {
+        match self.method {
+            VpkMethod::OneSample => Self::value(&self.offsets, moveback),
+            VpkMethod::TwoSample => match TwoSample::from(moveback) {
+                TwoSample::One(o) => Self::value(&self.offsets, o),
+                TwoSample::Two { first, second } => {
+                    Some(Self::value(&self.offsets, first)? + Self::value(&self.offsets, second)?)
+                }
+            },
+        }
+    }
+
+    fn length(&self, length: usize) -> Option<u64> {
+        Self::value(&self.lengths, length)
+    }
+}
+
+/// Find a cheap series of literals and matches for `data[start..]` with `costs`
+fn parse(
+    data: &[u8],
+    start: usize,
+    settings: &LzssSettings,
+    method: VpkMethod,
+    matches: &AllMatches,
+    costs: &Costs,
+) -> Result<LzssPass, VpkError> {
+    let shortest = settings.max_uncoded + 1;
+    let length_costs: Vec<Option<u64>> = (0..=settings.max_encoded())
+        .map(|l| costs.length(l))
+        .collect();
+
+    // the cheapest cost to encode `data[start..i]`, and the (length, moveback) of the last token
+    let mut cost = vec![u64::MAX; data.len() + 1];
+    let mut step = vec![(1, 0); data.len() + 1];
+    cost[start] = 0;
+
+    for pos in start..data.len() {
+        let here = cost[pos];
+        if here + LITERAL_COST < cost[pos + 1] {
+            cost[pos + 1] = here + LITERAL_COST;
+            step[pos + 1] = (1, 0);
+        }
+
+        // a match can be cut short, so each length can use any match at least that long.
+        // Going from the longest match down, keep the cheapest offset of the longer matches.
+        // The longest match of each offset bit size is known, so one sample offsets always
+        // get the cheapest code. Two sample offset costs also depend on the remainder
+        // of the offset, so a cheaper two sample offset can still be missed
+        let found = matches.at(pos);
+        let mut cheapest: Option<(u64, usize)> = None;
+        for (i, &(len, moveback)) in found.iter().enumerate().rev() {
+            if let Some(c) = costs.offset(moveback) {
+                if cheapest.map_or(true, |(best, _)| c < best) {
+                    cheapest = Some((c, moveback));
+                }
+            }
+            let (offset_cost, moveback) = match cheapest {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let shorter = if i == 0 { shortest - 1 } else { found[i - 1].0 };
+            for l in shorter + 1..=len {
+                if let Some(length_cost) = length_costs[l] {
+                    let total = here + 1 + offset_cost + length_cost;
+                    if total < cost[pos + l] {
+                        cost[pos + l] = total;
+                        step[pos + l] = (l, moveback);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut tokens = Vec::new();
+    let mut end = data.len();
+    while end > start {
+        let (len, moveback) = step[end];
+        end -= len;
+        tokens.push((end, len, moveback));
+    }
+
+    let mut pass = LzssPass::new(tokens.len());
+    for &(start, len, moveback) in tokens.iter().rev() {
+        if moveback == 0 {
+            pass.add_uncoded(data[start]);
+        } else {
+            pass.add(match method {
+                VpkMethod::OneSample => LzssByte::Encoded(len, moveback),
+                VpkMethod::TwoSample => LzssByte::EncTwoSample(len, moveback.into()),
+            });
+        }
+    }
+    pass.decompressed_size = Some((data.len() - start).try_into()?);
+
+    Ok(pass)
+}
@@ -0,0 +1,226 @@
+//! Encode an edited version of a decoded `vpk0` file by reusing the tokens
+//! that the edit doesn't affect, and parsing only the bytes around the edit again.
+
+use super::{
+    huffman::{EncodedMaps, UserTree},
+    lzss::{self, LazyPolicy, LzssByte, LzssPass},
+    optimal, token_pass, Encoder, TreeOptions,
+};
+use crate::{decode::DecodedTokens, errors::VpkError, format::Token};
+use std::{
+    io::{Read, Write},
+    ops::Range,
+};
+
+/// The tokens of the original file that are reused for the edited data
+#[derive(Debug, PartialEq, Eq)]
+struct Edit {
+    /// `tokens[..before]` come before the edit
+    before: usize,
+    /// `tokens[after..]` come after the edit
+    after: usize,
+    /// the bytes of the edited data between the reused tokens
+    range: Range<usize>,
+}
+
+impl Edit {
+    /// Find the tokens of `original` that still decode to the same bytes in `data`
+    fn find(original: &DecodedTokens, data: &[u8]) -> Self {
+        let old = &original.data;
+        let tokens = &original.tokens;
+        let method = original.header.method;
+
+        let prefix = old.iter().zip(data).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(data[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let changed_end = old.len() - suffix;
+
+        let mut before = 0;
+        let mut start = 0;
+        while before < tokens.len() && start + tokens[before].len() <= prefix {
+            start += tokens[before].len();
+            before += 1;
+        }
+
+        // a copy-back after the edit is only the same if it copies from the same distance
+        // and the copied bytes didn't change
+        let unchanged = |token: Token, pos: usize| match token.move_back(method) {
+            0 => true,
+            move_back => {
+                let src = pos.saturating_sub(move_back);
+                src >= changed_end || (old.len() == data.len() && src + token.len() <= prefix)
+            }
+        };
+
+        let mut after = tokens.len();
+        let mut end = old.len();
+        while after > before {
+            let token = tokens[after - 1];
+            let pos = end - token.len();
+            if pos < changed_end || !unchanged(token, pos) {
+                break;
+            }
+            end = pos;
+            after -= 1;
+        }
+
+        Self {
+            before,
+            after,
+            range: start..data.len() - (old.len() - end),
+        }
+    }
+}
+
+/// Encode `data` as an edited version of `original` with the settings in `opts`
+pub(super) fn reencode<R: Read>(
+    opts: &mut Encoder<'_, R>,
+    original: &DecodedTokens,
+    data: &[u8],
+) -> Result<(LzssPass, EncodedMaps), VpkError> {
+    let Encoder {
+        settings,
+        ref mut log,
+        backend,
+        trees,
+        lazy,
+        ref mut policy,
+        optimal,
+        ..
+    } = opts;
+    let method = original.header.method;
+
+    // the original trees are used, unless others were set
+    let trees = TreeOptions {
+        offsets: (trees.offsets.clone())
+            .or_else(|| Some(UserTree::Parsed(original.trees.offsets.clone()))),
+        lengths: (trees.lengths.clone())
+            .or_else(|| Some(UserTree::Parsed(original.trees.lengths.clone()))),
+        ..*trees
+    };
+
+    let edit = Edit::find(original, data);
+    if let Some(wtr) = log.as_mut() {
+        writeln!(
+            wtr,
+            "Reusing {} tokens before and {} tokens after the edit at {:?}",
+            edit.before,
+            original.tokens.len() - edit.after,
+            edit.range
+        )
+        .map_err(VpkError::Log)?;
+    }
+
+    // the full parse with the original tokens around a parse of the edit
+    let splice = |parsed: LzssPass| {
+        let before = &original.tokens[..edit.before];
+        let after = &original.tokens[edit.after..];
+        let tokens = (before.iter().copied())
+            .chain(parsed.buf.iter().map(LzssByte::to_token))
+            .chain(after.iter().copied());
+
+        token_pass(method, tokens)
+    };
+
+    // matches in the edit can copy from the window before it
+    let history = edit.range.start.min(settings.window_size());
+    let window = &data[edit.range.start - history..edit.range.end];
+
+    let mut lazy = *lazy;
+    let policy = match policy {
+        Some(p) => &mut **p,
+        None => &mut lazy as &mut dyn LazyPolicy,
+    };
+    let parsed = lzss::compress_after(window, history, *settings, method, *backend, policy, log)?;
+    let lzss = splice(parsed)?;
+    let maps = trees.build(&lzss)?;
+
+    if *optimal && !edit.range.is_empty() {
+        let build = |parsed| {
+            let pass = splice(parsed)?;
+            trees.build(&pass).map(|maps| (pass, maps))
+        };
+        optimal::optimize(window, history, *settings, method, (lzss, maps), build, log)
+    } else {
+        Ok((lzss, maps))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Decoder, Encoder};
+
+    fn edit_of(original: &[u8], edited: &[u8]) -> (DecodedTokens, Edit) {
+        let compressed = Encoder::for_bytes(original).encode_to_vec().unwrap();
+        let decoded = Decoder::for_bytes(&compressed).decode_tokens().unwrap();
+        let edit = Edit::find(&decoded, edited);
+        (decoded, edit)
+    }
+
+    #[test]
+    fn unedited() {
+        let (decoded, edit) = edit_of(b"sam I am I am sam", b"sam I am I am sam");
+        let all = decoded.tokens.len();
+        assert_eq!(
+            edit,
+            Edit {
+                before: all,
+                after: all,
+                range: 17..17
+            }
+        );
+    }
+
+    #[test]
+    fn same_size_edit() {
+        let original = b"abcdefgh abcdefgh 12345678 12345678 abcdefgh";
+        let (decoded, edit) = edit_of(original, b"abcdefgh abcdefgh 1234X678 12345678 abcdefgh");
+
+        // the copy of "12345678 " copies from the edit, but the last copy of "abcdefgh" is kept
+        let all = decoded.tokens.len();
+        assert_eq!(
+            edit,
+            Edit {
+                before: 14,
+                after: all - 1,
+                range: 22..36
+            }
+        );
+    }
+
+    #[test]
+    fn insertion_keeps_copies_after_it() {
+        let (decoded, edit) = edit_of(b"0123 abcdefgh abcdefgh", b"01X23 abcdefgh abcdefgh");
+        assert_eq!(decoded.tokens.len(), 14);
+        assert_eq!(
+            edit,
+            Edit {
+                before: 2,
+                after: 2,
+                range: 2..3
+            }
+        );
+    }
+
+    #[test]
+    fn insertion_parses_moved_copies() {
+        let original = b"abcdefgh abcdefgh 12345678 12345678";
+        let (decoded, edit) = edit_of(original, b"abcdefgh abcdefgh --12345678 12345678");
+
+        // the last copy-back starts with the space before the insertion
+        let all = decoded.tokens.len();
+        assert_eq!(
+            edit,
+            Edit {
+                before: 10,
+                after: all,
+                range: 18..37
+            }
+        );
+    }
+}
@@ -1,9 +1,12 @@
-use std::{
-    error::Error,
-    fmt, io,
+use crate::format::{HuffmanTree, OffsetSample, VpkMethod};
+use alloc::{boxed::Box, string::String};
+use core::{
+    fmt,
     num::{ParseIntError, TryFromIntError},
     str,
 };
+#[cfg(feature = "std")]
+use std::{error::Error, io};
 
 /// Possible errors that arise from compressing or decompressing a `vpk0` binary
 #[derive(Debug)]
@@ -12,11 +15,137 @@ pub enum VpkError {
     InvalidHeader(String),
     InvalidMethod(u8),
     BadLookBack(usize, usize),
+    BufferTooSmall(usize, usize),
+    OutputTooLarge(usize, usize),
+    RatioExceeded(usize, u64),
+    CopyOvershoot(usize, usize),
+    NonZeroPadding(u8),
+    TrailingData,
+    LeafTooWide(u8),
+    TooManyTreeEntries(usize),
+    /// A tree has a code longer than [`HuffmanTree::MAX_CODE_LEN`](crate::format::HuffmanTree::MAX_CODE_LEN) bits
+    TreeTooDeep(u32),
     BadTreeEncoding,
     BadUserTree(EncodeTreeParseErr),
     InputTooBig(TryFromIntError),
     Utf8Error(str::Utf8Error),
+    UnexpectedEof,
+    #[cfg(feature = "std")]
     Io(io::Error),
+    /// A bit size found while encoding is larger than the largest leaf of a user-provided tree
+    UserTreeTooSmall(u8, u8),
+    /// There is no Huffman code for a value of this many bits
+    MissingTreeCode(u8),
+    /// The encoded data doesn't have a decompressed size for the header
+    MissingDecompressedSize,
+    /// A copy-back token doesn't match the sample method of the file being written
+    WrongTokenMethod(VpkMethod),
+    /// Two sample offset values that would be decoded as different values
+    InvalidOffsetSample(OffsetSample),
+    /// A lazy matching depth that is zero or larger than
+    /// [`LazyMatching::MAX_DEPTH`](crate::LazyMatching::MAX_DEPTH)
+    #[cfg(feature = "std")]
+    InvalidLazyDepth(usize),
+    /// Writing to the encoder's log failed
+    #[cfg(feature = "std")]
+    Log(io::Error),
+    /// A line of a listing couldn't be assembled by [`assemble`](crate::assemble):
+    /// the line number, and what was wrong with it
+    #[cfg(feature = "std")]
+    BadListing(usize, String),
+    /// An error that happened while decoding the token at an [`ErrorLocation`].
+    /// Match on [`kind`](VpkError::kind) to get the underlying error.
+    Decode(ErrorLocation, Box<VpkError>),
+    /// An error that happened while reading or checking one of the Huffman trees.
+    /// Match on [`kind`](VpkError::kind) to get the underlying error.
+    Tree(TreeKind, Box<VpkError>),
+}
+
+impl VpkError {
+    /// Get the underlying error, without any location or tree information
+    /// ```
+    /// # use vpk0::errors::{TreeKind, VpkError};
+    /// // a header without any trees
+    /// let data = b"vpk0\x00\x00\x00\x08\x00";
+    /// let err = vpk0::decode_bytes(data).unwrap_err();
+    /// assert_eq!(err.tree(), Some(TreeKind::Offsets));
+    /// assert!(matches!(err.kind(), VpkError::UnexpectedEof));
+    /// ```
+    pub fn kind(&self) -> &VpkError {
+        match self {
+            VpkError::Decode(_, e) | VpkError::Tree(_, e) => e.kind(),
+            e => e,
+        }
+    }
+
+    /// Get where in the `vpk0` stream a decoding error happened
+    pub fn location(&self) -> Option<ErrorLocation> {
+        match self {
+            VpkError::Decode(loc, _) => Some(*loc),
+            VpkError::Tree(_, e) => e.location(),
+            _ => None,
+        }
+    }
+
+    /// Get the offset in bits into the compressed input where a decoding error happened
+    pub fn bit_offset(&self) -> Option<u64> {
+        self.location().map(|loc| loc.bit_offset)
+    }
+
+    /// Get the number of bytes that were decompressed before a decoding error happened
+    pub fn output_position(&self) -> Option<usize> {
+        self.location().map(|loc| loc.output_position)
+    }
+
+    /// Get the index of the token (uncoded byte or copy-back) where a decoding error happened
+    pub fn token_index(&self) -> Option<usize> {
+        self.location().map(|loc| loc.token_index)
+    }
+
+    /// Get which Huffman tree failed to parse or validate
+    pub fn tree(&self) -> Option<TreeKind> {
+        match self {
+            VpkError::Tree(kind, _) => Some(*kind),
+            VpkError::Decode(_, e) => e.tree(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn at(self, location: ErrorLocation) -> Self {
+        VpkError::Decode(location, Box::new(self))
+    }
+
+    pub(crate) fn in_tree(self, kind: TreeKind) -> Self {
+        VpkError::Tree(kind, Box::new(self))
+    }
+}
+
+/// The position in a `vpk0` stream where a decoding error happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorLocation {
+    /// Number of bits of compressed input read when the error happened,
+    /// including the header and trees
+    pub bit_offset: u64,
+    /// Number of bytes decompressed before the failing token
+    pub output_position: usize,
+    /// Index of the failing token, counting both uncoded bytes and copy-backs
+    pub token_index: usize,
+}
+
+/// The two Huffman trees stored in a `vpk0` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeKind {
+    Offsets,
+    Lengths,
+}
+
+impl fmt::Display for TreeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TreeKind::Offsets => write!(f, "offsets"),
+            TreeKind::Lengths => write!(f, "lengths"),
+        }
+    }
 }
 
 impl fmt::Display for VpkError {
@@ -31,24 +160,102 @@ impl fmt::Display for VpkError {
                 "Bad input file: asked to move back {} bytes in buffer of only {} bytes",
                 mb, size
             ),
+            VpkError::BufferTooSmall(needed, size) => write!(
+                f,
+                "Output buffer of {} bytes is too small for {} bytes of decompressed data",
+                size, needed
+            ),
+            VpkError::OutputTooLarge(size, max) => write!(
+                f,
+                "Decompressed size of {} bytes is larger than the limit of {} bytes",
+                size, max
+            ),
+            VpkError::RatioExceeded(output, input) => write!(
+                f,
+                "Decompressed {} bytes from only {} compressed bytes, which exceeds the maximum ratio",
+                output, input
+            ),
+            VpkError::CopyOvershoot(end, size) => write!(
+                f,
+                "Copy-back ends at byte {}, past the decompressed size of {} bytes",
+                end, size
+            ),
+            VpkError::NonZeroPadding(bits) => {
+                write!(f, "Padding bits after last token are not zero: {:#b}", bits)
+            }
+            VpkError::TrailingData => write!(f, "Unexpected data after end of vpk0 stream"),
+            VpkError::LeafTooWide(size) => {
+                write!(f, "Huffman tree leaf of {} bits is wider than 32 bits", size)
+            }
+            VpkError::TooManyTreeEntries(n) => {
+                write!(f, "Huffman tree has too many entries ({})", n)
+            }
+            VpkError::TreeTooDeep(len) => {
+                write!(
+                    f,
+                    "Huffman tree has a code of {} bits, which is longer than {} bits",
+                    len,
+                    HuffmanTree::MAX_CODE_LEN
+                )
+            }
             VpkError::BadTreeEncoding => write!(f, "Huffman tree value couldn't be read"),
             VpkError::BadUserTree(_) => {
                 write!(f, "Issue parsing user-provided huffman code tree string")
             }
             VpkError::InputTooBig(_) => write!(f, "Input file size too big to fit in 32-bit word"),
             VpkError::Utf8Error(_) => write!(f, "Couldn't read magic bytes"),
+            VpkError::UnexpectedEof => write!(f, "Unexpected end of vpk0 data"),
+            #[cfg(feature = "std")]
             VpkError::Io(_) => write!(f, "IO issue"),
+            VpkError::UserTreeTooSmall(size, largest) => write!(
+                f,
+                "Found a {} bit value, but the largest leaf of the user-provided tree is {} bits",
+                size, largest
+            ),
+            VpkError::MissingTreeCode(size) => {
+                write!(f, "No Huffman code for a {} bit value", size)
+            }
+            VpkError::WrongTokenMethod(method) => {
+                write!(f, "Copy-back token can't be written to a {} file", method)
+            }
+            VpkError::InvalidOffsetSample(sample) => {
+                write!(f, "Offset samples {:?} can't be decoded as written", sample)
+            }
+            VpkError::MissingDecompressedSize => {
+                write!(f, "Decompressed size of the encoded data is unknown")
+            }
+            #[cfg(feature = "std")]
+            VpkError::InvalidLazyDepth(depth) => write!(
+                f,
+                "Lazy matching depth of {} is not between 1 and {}",
+                depth,
+                crate::LazyMatching::MAX_DEPTH
+            ),
+            #[cfg(feature = "std")]
+            VpkError::Log(_) => write!(f, "Couldn't write to the encoding log"),
+            #[cfg(feature = "std")]
+            VpkError::BadListing(line, reason) => {
+                write!(f, "Invalid listing on line {}: {}", line, reason)
+            }
+            VpkError::Decode(loc, e) => write!(
+                f,
+                "{} (at input bit {}, output byte {}, token {})",
+                e, loc.bit_offset, loc.output_position, loc.token_index
+            ),
+            VpkError::Tree(kind, e) => write!(f, "{} (in {} tree)", e, kind),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl Error for VpkError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             VpkError::BadUserTree(e) => Some(e as &dyn Error),
             VpkError::InputTooBig(e) => Some(e as &dyn Error),
             VpkError::Utf8Error(e) => Some(e as &dyn Error),
-            VpkError::Io(e) => Some(e as &dyn Error),
+            VpkError::Io(e) | VpkError::Log(e) => Some(e as &dyn Error),
+            VpkError::Decode(_, e) | VpkError::Tree(_, e) => Some(e.as_ref() as &dyn Error),
             _ => None,
         }
     }
@@ -60,12 +267,29 @@ impl From<EncodeTreeParseErr> for VpkError {
     }
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for VpkError {
     fn from(e: io::Error) -> Self {
         Self::Io(e)
     }
 }
 
+#[cfg(feature = "std")]
+impl From<VpkError> for io::Error {
+    fn from(e: VpkError) -> Self {
+        let kind = match e.kind() {
+            VpkError::Io(inner) | VpkError::Log(inner) => inner.kind(),
+            VpkError::UnexpectedEof => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+
+        match e {
+            VpkError::Io(e) => e,
+            e => io::Error::new(kind, e),
+        }
+    }
+}
+
 impl From<TryFromIntError> for VpkError {
     fn from(e: TryFromIntError) -> Self {
         Self::InputTooBig(e)
@@ -89,6 +313,7 @@ pub enum EncodeTreeParseErr {
     LexUnexp(char, usize),
     ParseUnexp(&'static str, usize),
     ParseUnexpEnd,
+    TooDeep(usize),
 }
 
 impl fmt::Display for EncodeTreeParseErr {
@@ -104,10 +329,19 @@ impl fmt::Display for EncodeTreeParseErr {
                 write!(f, "Unexpected token '{}' at pos {}", s, p)
             }
             EncodeTreeParseErr::ParseUnexpEnd => write!(f, "Unexpected end of tokens"),
+            EncodeTreeParseErr::TooDeep(p) => {
+                write!(
+                    f,
+                    "Tree has codes longer than {} bits at pos {}",
+                    HuffmanTree::MAX_CODE_LEN,
+                    p
+                )
+            }
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl Error for EncodeTreeParseErr {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
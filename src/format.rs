@@ -20,7 +20,8 @@
 //! After the header, there are two linearly encoded Huffman trees: one for the offsets,
 //! and one for the lengths.
 //! The two trees can be extracted into their `String` representations as [`TreeInfo`]
-//! by using [`vpk_info()`].
+//! by using [`vpk_info()`], or as [`HuffmanTree`]s by using
+//! [`Decoder::huffman_trees`](crate::Decoder::huffman_trees).
 //!
 //! Tree leafs are encoded as a `0` followed by an eight bit value leaf.
 //! Tree nodes are encoded by a `1`, and combine the most recent two nodes/leaves.
@@ -87,12 +88,19 @@
 //! ```
 //! [`vpk_info()`]: crate::vpk_info
 
+use crate::bits::BitReader;
 use crate::errors::VpkError;
-use bitstream_io::{BitReader, BitWriter, BE};
-use std::convert::TryInto;
-use std::fmt;
+use alloc::{vec, vec::Vec};
+use core::convert::TryInto;
+use core::fmt;
+use core::str;
+
+#[cfg(not(feature = "std"))]
+use crate::io::Read;
+#[cfg(feature = "std")]
+use bitstream_io::{BitWriter, BE};
+#[cfg(feature = "std")]
 use std::io::{Read, Write};
-use std::str;
 
 // re-export the string representations of the Huffman trees
 // makes more sense to be here for users, imho
@@ -123,6 +131,66 @@ impl fmt::Display for VpkMethod {
     }
 }
 
+/// The raw offset value(s) stored in a `vpk0` file for an encoded copy-back
+///
+/// One sample files always store a single value.
+/// Two sample files store two values when the first value is less than three;
+/// see [`VpkMethod`] for how they are combined.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum OffsetSample {
+    One(usize),
+    Two(usize, usize),
+}
+
+impl OffsetSample {
+    /// Convert the raw sample(s) into the number of bytes to move back in the output
+    /// for a file encoded with `method`.
+    /// Bad two sample values wrap around instead of panicking.
+    pub fn move_back(self, method: VpkMethod) -> usize {
+        match (method, self) {
+            (VpkMethod::OneSample, Self::One(v)) | (VpkMethod::OneSample, Self::Two(v, _)) => v,
+            (VpkMethod::TwoSample, Self::One(v)) => (v << 2).wrapping_sub(8),
+            (VpkMethod::TwoSample, Self::Two(l, u)) => (l + 1 + (u << 2)).wrapping_sub(8),
+        }
+    }
+}
+
+/// A literal byte or a copy-back in the LZSS encoded data of a `vpk0` file
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Token {
+    /// An uncoded byte
+    Literal(u8),
+    /// Copy `length` bytes from `offset` bytes back in the output, in a one sample file
+    Match { offset: usize, length: usize },
+    /// Copy `length` bytes from the raw offset samples of a two sample file
+    TwoSampleMatch { offset: OffsetSample, length: usize },
+}
+
+impl Token {
+    /// The number of bytes this token adds to the output
+    pub fn len(&self) -> usize {
+        match *self {
+            Self::Literal(..) => 1,
+            Self::Match { length, .. } | Self::TwoSampleMatch { length, .. } => length,
+        }
+    }
+
+    /// Returns `true` for a copy-back of zero bytes
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of bytes to move back for a copy-back in a file encoded with `method`,
+    /// or zero for a literal
+    pub fn move_back(&self, method: VpkMethod) -> usize {
+        match *self {
+            Self::Literal(..) => 0,
+            Self::Match { offset, .. } => OffsetSample::One(offset).move_back(method),
+            Self::TwoSampleMatch { offset, .. } => offset.move_back(method),
+        }
+    }
+}
+
 /// The information stored at the start of a `vpk0` file
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct VpkHeader {
@@ -148,13 +216,14 @@ impl VpkHeader {
         Ok(Self { size, method })
     }
     /// Convenience function to read the `vpk0` header from a bitstream
-    pub(crate) fn from_bitreader<R: Read>(reader: &mut BitReader<R, BE>) -> Result<Self, VpkError> {
+    pub(crate) fn from_bitreader<R: Read>(reader: &mut BitReader<R>) -> Result<Self, VpkError> {
         let mut header = [0u8; 9];
         reader.read_bytes(&mut header)?;
 
         Self::from_array(&header)
     }
     /// Write out `self` to the big endian `BitWriter` to match the vpk format
+    #[cfg(feature = "std")]
     pub(crate) fn write<W: Write>(&self, wtr: &mut BitWriter<W, BE>) -> Result<(), VpkError> {
         wtr.write_bytes(b"vpk0")?; // 0..4
         wtr.write(32, self.size)?; // 4..8
@@ -168,6 +237,191 @@ impl VpkHeader {
     }
 }
 
+/// A Huffman tree of bit sizes, like the offsets and lengths trees in a `vpk0` file
+///
+/// A tree can be parsed from its text representation (e.g. `"(1, (4, 7))"`) with
+/// [`str::parse`], read from the raw bits in a `vpk0` header with [`from_bits`],
+/// or built from the frequency of each bit size with [`from_frequencies`].
+/// It can be written back out as text with [`Display`](fmt::Display),
+/// or as bits with [`to_bits`].
+/// ```
+/// # use vpk0::format::HuffmanTree;
+/// let tree: HuffmanTree = "(1, (4, 7))".parse().unwrap();
+/// let bits = tree.to_bits();
+/// assert_eq!(HuffmanTree::from_bits(&bits).unwrap(), tree);
+///
+/// // a value of 7 needs three bits, so it uses the `10` code for the four bit leaf
+/// let leaf = tree.leaf_for(7).unwrap();
+/// assert_eq!((leaf.bit_size, leaf.code, leaf.code_len), (4, 0b10, 2));
+/// assert_eq!(tree.cost(vec![7, 1, 100]), Some(6 + 2 + 9));
+/// assert_eq!(tree.to_string(), "(1, (4, 7))");
+/// ```
+/// [`from_bits`]: HuffmanTree::from_bits
+/// [`from_frequencies`]: HuffmanTree::from_frequencies
+/// [`to_bits`]: HuffmanTree::to_bits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanTree(VpkTree);
+
+/// A leaf of a [`HuffmanTree`] and its Huffman code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TreeLeaf {
+    /// The number of bits in the values that use this leaf
+    pub bit_size: u8,
+    /// The Huffman code for this leaf, stored in the lowest `code_len` bits
+    pub code: u32,
+    /// The length of the Huffman code in bits
+    pub code_len: u32,
+}
+
+/// The offsets and lengths Huffman trees of a `vpk0` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanTrees {
+    pub offsets: HuffmanTree,
+    pub lengths: HuffmanTree,
+}
+
+impl HuffmanTree {
+    /// The longest Huffman code that a `HuffmanTree` can have
+    pub const MAX_CODE_LEN: u32 = 32;
+
+    /// Read a tree from the start of `data`, in the same format as a `vpk0` header.
+    /// Use [`bit_len`](HuffmanTree::bit_len) to find where the tree ended.
+    pub fn from_bits(data: &[u8]) -> Result<Self, VpkError> {
+        let tree = VpkTree::from_bitreader(&mut BitReader::new(data))?;
+        Self::try_from_vpk(tree)
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn as_vpk(&self) -> &VpkTree {
+        &self.0
+    }
+
+    pub(crate) fn try_from_vpk(tree: VpkTree) -> Result<Self, VpkError> {
+        let depth = max_depth(&tree.entries);
+        if depth > Self::MAX_CODE_LEN {
+            Err(VpkError::TreeTooDeep(depth))
+        } else {
+            Ok(Self(tree))
+        }
+    }
+
+    /// Build a tree from the number of times each bit size is used.
+    /// Bit sizes that are not used do not need to be included.
+    /// ```
+    /// # use vpk0::{format::HuffmanTree, TreeMode};
+    /// let freqs = vec![(3, 40), (5, 10), (8, 1)];
+    /// let tree = HuffmanTree::from_frequencies(freqs, TreeMode::Huffman);
+    /// assert_eq!(tree.to_string(), "((8, 5), 3)");
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn from_frequencies<I>(freqs: I, mode: crate::TreeMode) -> Self
+    where
+        I: IntoIterator<Item = (u8, u64)>,
+    {
+        let mut found = std::collections::BTreeMap::new();
+        for (size, freq) in freqs {
+            *found.entry(size).or_insert(0) += freq;
+        }
+        Self(crate::encode::huffman::build_tree(&found, mode))
+    }
+
+    /// All of the leaves in the tree, from left to right
+    pub fn leaves(&self) -> Vec<TreeLeaf> {
+        let entries = &self.0.entries;
+        let mut leaves = Vec::new();
+        // (entry, code, code length)
+        let mut stack = match entries.len() {
+            0 => Vec::new(),
+            len => vec![(len - 1, 0, 0)],
+        };
+        while let Some((idx, code, code_len)) = stack.pop() {
+            match entries[idx] {
+                TreeEntry::Leaf(bit_size) => leaves.push(TreeLeaf {
+                    bit_size,
+                    code,
+                    code_len,
+                }),
+                TreeEntry::Node { left, right } => {
+                    // the left side is popped first
+                    stack.push((right, (code << 1) | 1, code_len + 1));
+                    stack.push((left, code << 1, code_len + 1));
+                }
+            }
+        }
+        leaves
+    }
+
+    /// The leaf used to encode `value`: the smallest leaf that can hold the value,
+    /// with the shortest code if there are equal leaves.
+    /// Returns `None` if the value is too large for every leaf.
+    pub fn leaf_for(&self, value: usize) -> Option<TreeLeaf> {
+        let needed = (usize::MAX.count_ones() - value.leading_zeros()) as u8;
+        self.leaves()
+            .into_iter()
+            .filter(|leaf| leaf.bit_size >= needed)
+            .min_by_key(|leaf| (leaf.bit_size, leaf.code_len))
+    }
+
+    /// The total number of bits to encode every value in `values` with this tree,
+    /// including the Huffman codes. Returns `None` if a value is too large for the tree.
+    pub fn cost<I: IntoIterator<Item = usize>>(&self, values: I) -> Option<u64> {
+        values.into_iter().try_fold(0, |total, value| {
+            self.leaf_for(value)
+                .map(|leaf| total + leaf.code_len as u64 + leaf.bit_size as u64)
+        })
+    }
+
+    /// The number of bits used to store this tree in a `vpk0` file
+    pub fn bit_len(&self) -> usize {
+        let leaves = self
+            .0
+            .entries
+            .iter()
+            .filter(|e| matches!(e, TreeEntry::Leaf(..)));
+        let leaves = leaves.count();
+        // nine bits for each leaf, one for each node, and one to end the tree
+        leaves * 9 + (self.0.entries.len() - leaves) + 1
+    }
+
+    /// Write this tree in the same format as a `vpk0` header.
+    /// The last byte is padded with zeros.
+    pub fn to_bits(&self) -> Vec<u8> {
+        let mut out = vec![0u8; (self.bit_len() + 7) / 8];
+        let mut pos = 0;
+        let mut push = |bits: u32, n: u32| {
+            for i in (0..n).rev() {
+                if (bits >> i) & 1 == 1 {
+                    out[pos / 8] |= 0x80 >> (pos % 8);
+                }
+                pos += 1;
+            }
+        };
+        for entry in &self.0.entries {
+            match *entry {
+                TreeEntry::Leaf(size) => push(size as u32, 9),
+                TreeEntry::Node { .. } => push(1, 1),
+            }
+        }
+        push(1, 1);
+        out
+    }
+}
+
+#[cfg(feature = "std")]
+impl str::FromStr for HuffmanTree {
+    type Err = crate::errors::EncodeTreeParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        crate::encode::huffman::parse_vpk_tree(s).map(Self)
+    }
+}
+
+impl fmt::Display for HuffmanTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
 /// A Huffman tree node or leaf designed to be stored in an array
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum TreeEntry {
@@ -177,20 +431,28 @@ pub(crate) enum TreeEntry {
 }
 
 /// An array based huffman tree
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub(crate) struct VpkTree {
     entries: Vec<TreeEntry>,
+    table: LookupTable,
 }
 
 impl VpkTree {
+    fn new(entries: Vec<TreeEntry>) -> Self {
+        let table = LookupTable::new(&entries);
+        Self { entries, table }
+    }
     /// Create an empty tree.
     /// This will be written to the output buffer as a single true bit (1)
+    #[cfg(feature = "std")]
     pub(crate) fn empty() -> Self {
-        Self {
-            entries: Vec::new(),
-        }
+        Self::new(Vec::new())
+    }
+    #[cfg(feature = "std")]
+    pub(crate) fn entries(&self) -> &[TreeEntry] {
+        &self.entries
     }
-    pub(crate) fn from_bitreader<R: Read>(bits: &mut BitReader<R, BE>) -> Result<Self, VpkError> {
+    pub(crate) fn from_bitreader<R: Read>(bits: &mut BitReader<R>) -> Result<Self, VpkError> {
         let mut entries: Vec<TreeEntry> = Vec::new();
         let mut buf: Vec<usize> = Vec::new();
 
@@ -209,23 +471,23 @@ impl VpkTree {
                 });
             } else {
                 // add a leaf node with an 8-bit value
-                entries.push(TreeEntry::Leaf(bits.read(8)?));
+                entries.push(TreeEntry::Leaf(bits.read(8)? as u8));
             }
             // store a reference to new leaf or node in the buf for later combination
             buf.push(new_entry_idx);
         }
 
-        Ok(Self { entries })
+        Ok(Self::new(entries))
     }
     /// Use `BitReader` `bits` to read a value out from this `HuffTree`
-    pub(crate) fn read_value<R: Read>(&self, bits: &mut BitReader<R, BE>) -> Result<u32, VpkError> {
+    pub(crate) fn read_value<R: Read>(&self, bits: &mut BitReader<R>) -> Result<u32, VpkError> {
         let tbl = &self.entries;
         let len = tbl.len();
         if len == 0 {
             return Ok(0);
         };
-        // tree starts from end
-        let mut idx = len - 1;
+        // tree starts from end, but skip ahead with the lookup table if possible
+        let mut idx = self.table.lookup(bits)?.unwrap_or(len - 1);
         while let TreeEntry::Node { left, right } = tbl[idx] {
             if bits.read_bit()? {
                 idx = right;
@@ -235,11 +497,80 @@ impl VpkTree {
         }
         // make a loop -> match set to just return this?
         match tbl[idx] {
-            TreeEntry::Leaf(size) => Ok(bits.read(size as u32)?),
+            TreeEntry::Leaf(size) if size <= 32 => bits.read(size as u32),
             _ => Err(VpkError::BadTreeEncoding),
         }
     }
+    /// Read a value like [`read_value`](VpkTree::read_value), along with the leaf and
+    /// Huffman code that were used
+    #[cfg(feature = "std")]
+    pub(crate) fn read_code<R: Read>(
+        &self,
+        bits: &mut BitReader<R>,
+    ) -> Result<(TreeLeaf, u32), VpkError> {
+        let tbl = &self.entries;
+        if tbl.is_empty() {
+            let leaf = TreeLeaf {
+                bit_size: 0,
+                code: 0,
+                code_len: 0,
+            };
+            return Ok((leaf, 0));
+        }
+
+        let (mut idx, mut code, mut code_len) = (tbl.len() - 1, 0, 0);
+        while let TreeEntry::Node { left, right } = tbl[idx] {
+            let bit = bits.read_bit()?;
+            code = (code << 1) | bit as u32;
+            code_len += 1;
+            idx = if bit { right } else { left };
+        }
+
+        match tbl[idx] {
+            TreeEntry::Leaf(bit_size) if bit_size <= 32 => {
+                let value = bits.read(bit_size as u32)?;
+                let leaf = TreeLeaf {
+                    bit_size,
+                    code,
+                    code_len,
+                };
+                Ok((leaf, value))
+            }
+            _ => Err(VpkError::BadTreeEncoding),
+        }
+    }
+    /// Check that this tree could be used by other decoders:
+    /// leaves must fit in a 32-bit value, and the tree can't be larger than
+    /// a tree with a leaf for every bit size from 0 to 32
+    pub(crate) fn check_strict(&self) -> Result<(), VpkError> {
+        const MAX_ENTRIES: usize = 33 * 2 - 1;
+
+        if self.entries.len() > MAX_ENTRIES {
+            return Err(VpkError::TooManyTreeEntries(self.entries.len()));
+        }
+
+        for entry in &self.entries {
+            match *entry {
+                TreeEntry::Leaf(size) if size > 32 => return Err(VpkError::LeafTooWide(size)),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+    /// Get the largest bit size stored in the leaves of this tree
+    #[cfg(feature = "std")]
+    pub(crate) fn max_leaf(&self) -> Option<u8> {
+        self.entries
+            .iter()
+            .filter_map(|e| match e {
+                TreeEntry::Leaf(size) => Some(*size),
+                TreeEntry::Node { .. } => None,
+            })
+            .max()
+    }
     /// Write `self` to the Big Endian `BitWriter` in the expected VPK format
+    #[cfg(feature = "std")]
     pub(crate) fn write<W: Write>(&self, wtr: &mut BitWriter<W, BE>) -> Result<(), VpkError> {
         for entry in &self.entries {
             match entry {
@@ -270,6 +601,15 @@ impl VpkTree {
     }
 }
 
+// the lookup table is built from the entries, so it is left out
+impl fmt::Debug for VpkTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VpkTree")
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
 impl fmt::Display for VpkTree {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.entries.is_empty() {
@@ -282,6 +622,89 @@ impl fmt::Display for VpkTree {
 
 impl From<Vec<TreeEntry>> for VpkTree {
     fn from(entries: Vec<TreeEntry>) -> Self {
-        Self { entries }
+        Self::new(entries)
+    }
+}
+
+/// A table to decode up to `bits` of a Huffman code at once.
+///
+/// Peeking `bits` bits and indexing into `entries` gives the tree entry
+/// that those bits lead to, and how many of the bits were used to get there.
+/// Codes longer than `bits` lead to a `TreeEntry::Node`, and the rest of the code
+/// is decoded one bit at a time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LookupTable {
+    bits: u32,
+    // (tree entry index, code bits used)
+    entries: Vec<(usize, u32)>,
+}
+
+impl LookupTable {
+    const MAX_BITS: u32 = 10;
+
+    fn new(tree: &[TreeEntry]) -> Self {
+        // empty and single leaf trees have no code bits to look up
+        let bits = max_depth(tree).min(Self::MAX_BITS);
+        if bits == 0 {
+            return Self {
+                bits,
+                entries: Vec::new(),
+            };
+        }
+
+        let root = tree.len() - 1;
+        let entries = (0..1usize << bits)
+            .map(|code| {
+                let mut idx = root;
+                let mut used = 0;
+                while used < bits {
+                    match tree[idx] {
+                        TreeEntry::Node { left, right } => {
+                            let bit = (code >> (bits - used - 1)) & 1;
+                            idx = if bit == 1 { right } else { left };
+                            used += 1;
+                        }
+                        TreeEntry::Leaf(..) => break,
+                    }
+                }
+                (idx, used)
+            })
+            .collect();
+
+        Self { bits, entries }
     }
+
+    /// Use the next bits in `rdr` to skip down the tree. Bytes are only read from `rdr`
+    /// when the buffered bits are not enough to finish the lookup, so the reader never
+    /// moves past the end of a valid code.
+    fn lookup<R: Read>(&self, rdr: &mut BitReader<R>) -> Result<Option<usize>, VpkError> {
+        if self.bits == 0 {
+            return Ok(None);
+        }
+
+        loop {
+            let (peeked, real) = rdr.peek_buffered(self.bits);
+            let (idx, used) = self.entries[peeked];
+            // if the code needs more bits than are buffered, padding was used
+            if used <= real {
+                rdr.consume(used);
+                return Ok(Some(idx));
+            }
+            rdr.fill_byte()?;
+        }
+    }
+}
+
+/// Get the length of the longest code in the array based `tree`
+fn max_depth(tree: &[TreeEntry]) -> u32 {
+    let mut depths = vec![0; tree.len()];
+    // children are always stored before their parents, and the root is last
+    for (i, entry) in tree.iter().enumerate().rev() {
+        if let TreeEntry::Node { left, right } = *entry {
+            depths[left] = depths[i] + 1;
+            depths[right] = depths[i] + 1;
+        }
+    }
+
+    depths.into_iter().max().unwrap_or(0)
 }
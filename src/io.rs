@@ -0,0 +1,32 @@
+//! Minimal I/O traits for decoding `vpk0` data without the standard library.
+//!
+//! When the `std` feature is disabled, [`Decoder`](crate::Decoder) reads from
+//! this module's [`Read`] trait instead of `std::io::Read`. It is implemented for `&[u8]`.
+
+use crate::errors::VpkError;
+use core::fmt;
+
+/// A `no_std` stand-in for `std::io::Read`
+pub trait Read {
+    /// Pull some bytes from this source into `buf`, returning how many bytes were read.
+    /// A return value of `0` means that the source is exhausted.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VpkError>;
+}
+
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VpkError> {
+        let n = buf.len().min(self.len());
+        let (a, b) = self.split_at(n);
+        buf[..n].copy_from_slice(a);
+        *self = b;
+
+        Ok(n)
+    }
+}
+
+/// A `no_std` stand-in for the formatting half of `std::io::Write`,
+/// so that the decoder's logging code compiles without the standard library.
+/// Logging can only be enabled with the `std` feature.
+pub(crate) trait Write {
+    fn write_fmt(&mut self, args: fmt::Arguments) -> Result<(), VpkError>;
+}
@@ -64,16 +64,34 @@
 //!
 //! ## Implementation Details
 //! This implementation is designed to be a byte-perfect match of the encoder used
-//! for *Super Smash Bros.* As of March 2021, the LZSS encoder is byte-matching,
-//! but the Huffman compression is not.
+//! for *Super Smash Bros.* Both the LZSS encoder and, with [`TreeMode::Nintendo`],
+//! the Huffman trees are byte-matching.
+//!
+//! Nintendo's encoder sorts the bit sizes of the offsets (or lengths), and then
+//! recursively splits them into two groups for the left and right side of the tree.
+//! A group becomes a leaf, which uses the largest bit size in the group, when there is
+//! no split that would save bits.
 //!
 //! The matching LZSS encoding scheme is: after a found match, look ahead at the next byte
 //! to see if there is a longer match. Continue checking the next byte until
 //! a smaller or no match is found.
 //!
-//! The encoder in this crate checks at most the next ten bytes,
+//! By default, the encoder in this crate checks at most the next ten bytes,
 //! as that was the maximum number necessary to match all 500 `vpk0` encoded files in *SSB64*.
-//! In the future, this parameter may become another option for [`Encoder`].
+//! Other depths and lazy matching policies can be set with [`Encoder::lazy_matching`].
+//!
+//! For a smaller output instead of a matching one, [`Encoder::optimal_parsing`] picks
+//! the literals and matches with the real bit costs of the Huffman trees.
+//!
+//! ## `no_std` Support
+//! The decoder can be used without the standard library by disabling the default `std` feature.
+//! It still requires the `alloc` crate. Without `std`, [`Decoder`] reads from
+//! `vpk0::io::Read`, which is implemented for `&[u8]`:
+//! ```toml
+//! [dependencies]
+//! vpk0 = { version = "0.8", default-features = false }
+//! ```
+//! The encoder, [`VpkReader`], file helpers, and logging are only available with `std`.
 //!
 //! ## Advanced Usages
 //! ### Getting info from a `vpk0` file
@@ -88,6 +106,10 @@
 //! println!("Offsets: {} || Lengths: {}", trees.offsets, trees.lengths);
 //! ```
 //!
+//! ### Editing the bits of a `vpk0` file
+//! [`disassemble`] writes the header, trees, and tokens of a file as text,
+//! and [`assemble`] turns the text back into the same bytes.
+//!
 //! ### Encode like a standard LZSS
 //! ```
 //! use vpk0::{Encoder, LzssSettings};
@@ -107,10 +129,33 @@
 //! [the Japanese BSSes since the late 80s]: https://web.archive.org/web/20160110174426/https://oku.edu.mie-u.ac.jp/~okumura/compression/history.html
 //! [Deflate]: https://en.m.wikipedia.org/wiki/Deflate
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+mod bits;
 mod decode;
+#[cfg(feature = "std")]
 mod encode;
 pub mod errors;
 pub mod format;
+#[cfg(not(feature = "std"))]
+pub mod io;
+#[cfg(feature = "std")]
+mod listing;
 
-pub use decode::{decode, decode_bytes, vpk_info, Decoder};
-pub use encode::{encode, encode_bytes, lzss::LzssSettings, Encoder, LzssBackend};
+#[cfg(feature = "std")]
+pub use decode::VpkReader;
+pub use decode::{
+    decode, decode_bytes, diff, vpk_info, BitCosts, DecodeEvent, DecodedTokens, Decoder, DiffToken,
+    PartialDecode, TokenDifference, VpkDiff,
+};
+#[cfg(feature = "std")]
+pub use encode::{
+    encode, encode_bytes,
+    infer::{infer_settings, InferredSettings, TokenMismatch},
+    lzss::{LazyMatching, LazyPolicy, LzssSettings, MatchCandidates},
+    write_tokens, AddedLeaves, Encoder, LzssBackend, TreeMode,
+};
+#[cfg(feature = "std")]
+pub use listing::{assemble, disassemble};
@@ -0,0 +1,431 @@
+//! A line-oriented text listing of the bits in a `vpk0` file
+//!
+//! [`disassemble`] turns a `vpk0` file into a listing, and [`assemble`] turns a listing
+//! back into the same bytes. Everything after a `;` is a comment. The lines are:
+//!
+//! * `header method=M size=S`: the sample method (`0` or `1`) and decompressed size
+//! * `offsets BITS` and `lengths BITS`: the bits of each tree, with a group for each leaf
+//!   (`0` then the 8 bit size) and node (`1`), and a final `1` to end the tree
+//! * `lit XX`: an uncoded byte in hex
+//! * `copy off=V len=V`: a copy-back. Two sample files have one or two `off=` fields,
+//!   with the raw values of the samples
+//! * `padding BITS`: the bits after the last token that fill the last byte
+//! * `trailing XX XX ...`: bytes after the end of the `vpk0` stream
+//!
+//! A value `V` is written as `CODE:VALUE`, with the Huffman code in binary and the value
+//! in decimal, so that the exact code is kept. When editing a listing, a value can also be
+//! written as just `VALUE` to use the smallest leaf that fits it.
+//! The bit offset and output position of each token are written in its comment.
+
+use crate::{
+    bits::BitReader,
+    errors::{TreeKind, VpkError},
+    format::{HuffmanTree, OffsetSample, TreeLeaf, VpkHeader, VpkMethod, VpkTree},
+};
+use bitstream_io::{BigEndian, BitWriter};
+use std::fmt::Write as _;
+
+/// Number of trailing bytes on each line of a listing
+const TRAILING_PER_LINE: usize = 16;
+
+/// Write the `vpk0` file in `bytes` as a text listing
+/// ```
+/// let compressed = vpk0::encode_bytes(b"sam I am I am sam").unwrap();
+/// let listing = vpk0::disassemble(&compressed).unwrap();
+/// assert!(listing.contains("lit 73"));
+///
+/// // change the first byte, and the copy of it at the end, to 'S'
+/// let patched = listing.replacen("lit 73", "lit 53", 1);
+/// let compressed = vpk0::assemble(&patched).unwrap();
+/// assert_eq!(vpk0::decode_bytes(&compressed).unwrap(), b"Sam I am I am Sam");
+/// ```
+pub fn disassemble(bytes: &[u8]) -> Result<String, VpkError> {
+    let mut src = BitReader::new(bytes);
+    let header = VpkHeader::from_bitreader(&mut src)?;
+    let mut out = String::new();
+
+    let method = match header.method {
+        VpkMethod::OneSample => 0,
+        VpkMethod::TwoSample => 1,
+    };
+    writeln!(out, "header method={} size={}", method, header.size).unwrap();
+
+    let offsets = read_tree(&mut src, TreeKind::Offsets, &mut out)?;
+    let lengths = read_tree(&mut src, TreeKind::Lengths, &mut out)?;
+
+    let mut position = 0usize;
+    while position < header.size as usize {
+        let bit_offset = src.position();
+        if !src.read_bit()? {
+            let byte = src.read(8)?;
+            writeln!(out, "lit {:02x} ; @{} {:04x}", byte, bit_offset, position).unwrap();
+            position += 1;
+            continue;
+        }
+
+        let (leaf, first) = offsets.as_vpk().read_code(&mut src)?;
+        let mut fields = format!("copy off={}", value_field(leaf, first));
+        let sample = match header.method {
+            VpkMethod::TwoSample if first < 3 => {
+                let (leaf, second) = offsets.as_vpk().read_code(&mut src)?;
+                write!(fields, " off={}", value_field(leaf, second)).unwrap();
+                OffsetSample::Two(first as usize, second as usize)
+            }
+            _ => OffsetSample::One(first as usize),
+        };
+        let (leaf, length) = lengths.as_vpk().read_code(&mut src)?;
+        write!(fields, " len={}", value_field(leaf, length)).unwrap();
+
+        let move_back = sample.move_back(header.method);
+        writeln!(
+            out,
+            "{} ; @{} {:04x} move back {}, length {}",
+            fields, bit_offset, position, move_back, length
+        )
+        .unwrap();
+        position = position.saturating_add(length as usize);
+    }
+
+    let padding = ((8 - src.position() % 8) % 8) as usize;
+    let bits = src.byte_align();
+    if padding > 0 {
+        writeln!(out, "padding {:0w$b}", bits, w = padding).unwrap();
+    }
+
+    let trailing = src.into_inner();
+    for line in trailing.chunks(TRAILING_PER_LINE) {
+        write!(out, "trailing").unwrap();
+        for byte in line {
+            write!(out, " {:02x}", byte).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+
+    Ok(out)
+}
+
+/// Read a tree, and write its line of the listing
+fn read_tree(
+    src: &mut BitReader<&[u8]>,
+    kind: TreeKind,
+    out: &mut String,
+) -> Result<HuffmanTree, VpkError> {
+    let tree = VpkTree::from_bitreader(src)
+        .and_then(HuffmanTree::try_from_vpk)
+        .map_err(|e| e.in_tree(kind))?;
+
+    // each entry starts with a 0 for a leaf and its 8 bit size, or a 1 for a node
+    let bits = tree.to_bits();
+    let bit = |i: usize| (bits[i / 8] >> (7 - i % 8)) & 1;
+    write!(out, "{}", kind).unwrap();
+    let mut i = 0;
+    while i < tree.bit_len() {
+        let len = if bit(i) == 0 { 9 } else { 1 };
+        write!(out, " ").unwrap();
+        for j in i..i + len {
+            write!(out, "{}", bit(j)).unwrap();
+        }
+        i += len;
+    }
+    writeln!(out, " ; {}", tree).unwrap();
+
+    Ok(tree)
+}
+
+fn value_field(leaf: TreeLeaf, value: u32) -> String {
+    match leaf.code_len {
+        0 => format!(":{}", value),
+        len => format!("{:0w$b}:{}", leaf.code, value, w = len as usize),
+    }
+}
+
+/// Write the `vpk0` file for a text `listing` from [`disassemble`]
+///
+/// Returns [`VpkError::BadListing`] with the line number if a line can't be assembled.
+pub fn assemble(listing: &str) -> Result<Vec<u8>, VpkError> {
+    let mut out = Vec::new();
+    let mut asm = Assembler {
+        wtr: BitWriter::endian(&mut out, BigEndian),
+        bits: 0,
+        stage: Stage::Header,
+        method: VpkMethod::OneSample,
+        trees: Vec::new(),
+    };
+
+    for (i, line) in listing.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        asm.line(line)
+            .map_err(|reason| VpkError::BadListing(i + 1, reason))?;
+    }
+
+    let end = listing.lines().count();
+    match asm.stage {
+        Stage::Header | Stage::Tree => {
+            return Err(VpkError::BadListing(
+                end,
+                "missing the header or trees".into(),
+            ));
+        }
+        Stage::Tokens => asm
+            .pad(None)
+            .map_err(|reason| VpkError::BadListing(end, reason))?,
+        Stage::Padded => (),
+    }
+
+    drop(asm);
+    Ok(out)
+}
+
+/// The part of the file that the next line of a listing is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Header,
+    Tree,
+    Tokens,
+    /// after the padding, where only trailing bytes can be added
+    Padded,
+}
+
+struct Assembler<'o> {
+    wtr: BitWriter<&'o mut Vec<u8>, BigEndian>,
+    /// number of bits written
+    bits: u64,
+    stage: Stage,
+    method: VpkMethod,
+    /// the offsets and lengths trees, and the leaves of each
+    trees: Vec<(HuffmanTree, Vec<TreeLeaf>)>,
+}
+
+impl Assembler<'_> {
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let mut fields = line.split_whitespace();
+        let keyword = fields.next().unwrap_or("");
+
+        match (keyword, self.stage) {
+            ("header", Stage::Header) => {
+                self.header(fields)?;
+                self.stage = Stage::Tree;
+            }
+            ("offsets", Stage::Tree) if self.trees.is_empty() => self.tree(fields)?,
+            ("lengths", Stage::Tree) if self.trees.len() == 1 => {
+                self.tree(fields)?;
+                self.stage = Stage::Tokens;
+            }
+            ("lit", Stage::Tokens) => {
+                let byte = one_field(fields)?;
+                let byte = u8::from_str_radix(byte, 16).map_err(|e| e.to_string())?;
+                self.write(1, 0)?;
+                self.write(8, byte as u32)?;
+            }
+            ("copy", Stage::Tokens) => self.copy(fields)?,
+            ("padding", Stage::Tokens) => {
+                self.pad(Some(one_field(fields)?))?;
+                self.stage = Stage::Padded;
+            }
+            ("trailing", Stage::Tokens) | ("trailing", Stage::Padded) => {
+                if self.stage == Stage::Tokens {
+                    self.pad(None)?;
+                    self.stage = Stage::Padded;
+                }
+                for byte in fields {
+                    let byte = u8::from_str_radix(byte, 16).map_err(|e| e.to_string())?;
+                    self.write(8, byte as u32)?;
+                }
+            }
+            _ => return Err(format!("unexpected `{}` line", keyword)),
+        }
+
+        Ok(())
+    }
+
+    fn header<'l, I: Iterator<Item = &'l str>>(&mut self, fields: I) -> Result<(), String> {
+        let (mut method, mut size) = (None, None);
+        for field in fields {
+            match split_field(field, '=') {
+                Some(("method", "0")) => method = Some(VpkMethod::OneSample),
+                Some(("method", "1")) => method = Some(VpkMethod::TwoSample),
+                Some(("size", s)) => size = Some(s.parse::<u32>().map_err(|e| e.to_string())?),
+                _ => return Err(format!("unexpected header field `{}`", field)),
+            }
+        }
+
+        let header = VpkHeader {
+            method: method.ok_or("missing `method=`")?,
+            size: size.ok_or("missing `size=`")?,
+        };
+        self.method = header.method;
+        header.write(&mut self.wtr).map_err(|e| e.to_string())?;
+        self.bits += 9 * 8;
+
+        Ok(())
+    }
+
+    fn tree<'l, I: Iterator<Item = &'l str>>(&mut self, fields: I) -> Result<(), String> {
+        let bits: Vec<u8> = fields
+            .flat_map(str::bytes)
+            .map(|b| match b {
+                b'0' => Ok(0),
+                b'1' => Ok(1),
+                _ => Err(format!("`{}` is not a bit", b as char)),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut packed = vec![0u8; (bits.len() + 7) / 8];
+        for (i, &bit) in bits.iter().enumerate() {
+            packed[i / 8] |= bit << (7 - i % 8);
+        }
+        let tree = HuffmanTree::from_bits(&packed).map_err(|e| e.to_string())?;
+        if tree.bit_len() != bits.len() {
+            return Err(format!(
+                "the tree ends after {} of the {} bits",
+                tree.bit_len(),
+                bits.len()
+            ));
+        }
+
+        let leaves = tree.leaves();
+        // values are at most 32 bits, like the decoder's
+        if let Some(leaf) = leaves.iter().find(|l| l.bit_size > 32) {
+            return Err(format!("a leaf has {} bits, more than 32", leaf.bit_size));
+        }
+
+        for &bit in &bits {
+            self.write(1, bit as u32)?;
+        }
+        self.trees.push((tree, leaves));
+
+        Ok(())
+    }
+
+    fn copy<'l, I: Iterator<Item = &'l str>>(&mut self, fields: I) -> Result<(), String> {
+        let mut offsets = Vec::new();
+        let mut length = None;
+        for field in fields {
+            match split_field(field, '=') {
+                Some(("off", v)) if length.is_none() => offsets.push(parse_value(v)?),
+                Some(("len", v)) if length.is_none() => length = Some(parse_value(v)?),
+                _ => return Err(format!("unexpected copy field `{}`", field)),
+            }
+        }
+        let length = length.ok_or("missing `len=`")?;
+
+        // the decoder only reads a second sample if the first one is less than three
+        let samples = match (self.method, offsets.first()) {
+            (_, None) => 0,
+            (VpkMethod::TwoSample, Some(&(_, first))) if first < 3 => 2,
+            _ => 1,
+        };
+        if offsets.len() != samples.max(1) {
+            return Err(format!(
+                "expected {} offset values, found {}",
+                samples.max(1),
+                offsets.len()
+            ));
+        }
+
+        self.write(1, 1)?;
+        for value in offsets {
+            self.value(0, value)?;
+        }
+        self.value(1, length)
+    }
+
+    /// Write `value` with the offsets (0) or lengths (1) tree
+    fn value(&mut self, tree: usize, (code, value): Value) -> Result<(), String> {
+        let (tree, leaves) = &self.trees[tree];
+        let leaf = if leaves.is_empty() {
+            // an empty tree is read as a zero without any bits
+            TreeLeaf {
+                bit_size: 0,
+                code: 0,
+                code_len: 0,
+            }
+        } else {
+            match code {
+                Some((code, code_len)) => leaves
+                    .iter()
+                    .copied()
+                    .find(|l| l.code == code && l.code_len == code_len)
+                    .ok_or("no leaf has the code")?,
+                None => tree
+                    .leaf_for(value as usize)
+                    .ok_or("the value is too large for the tree")?,
+            }
+        };
+        if (value as u64)
+            .checked_shr(leaf.bit_size as u32)
+            .map_or(false, |rest| rest != 0)
+        {
+            return Err(format!(
+                "{} doesn't fit in the {} bits of its leaf",
+                value, leaf.bit_size
+            ));
+        }
+
+        self.write(leaf.code_len, leaf.code)?;
+        self.write(leaf.bit_size as u32, value)
+    }
+
+    /// Fill the rest of the last byte with `bits`, or with zeros
+    fn pad(&mut self, bits: Option<&str>) -> Result<(), String> {
+        let needed = ((8 - self.bits % 8) % 8) as u32;
+        let value = match bits {
+            Some(bits) if bits.len() as u32 == needed => {
+                u32::from_str_radix(bits, 2).map_err(|e| e.to_string())?
+            }
+            Some(bits) => {
+                return Err(format!(
+                    "{} bits of padding are needed, found {}",
+                    needed,
+                    bits.len()
+                ))
+            }
+            None => 0,
+        };
+
+        self.write(needed, value)
+    }
+
+    fn write(&mut self, n: u32, value: u32) -> Result<(), String> {
+        if n > 0 {
+            self.wtr.write(n, value).map_err(|e| e.to_string())?;
+            self.bits += n as u64;
+        }
+
+        Ok(())
+    }
+}
+
+/// A Huffman code and its length, if it was listed, and a value
+type Value = (Option<(u32, u32)>, u32);
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    let bad = |e: std::num::ParseIntError| format!("bad value `{}`: {}", s, e);
+    match split_field(s, ':') {
+        Some((code, value)) => {
+            let code = match code.len() {
+                0 => (0, 0),
+                len if len <= 32 => (u32::from_str_radix(code, 2).map_err(bad)?, len as u32),
+                _ => return Err(format!("the code of `{}` is too long", s)),
+            };
+            Ok((Some(code), value.parse().map_err(bad)?))
+        }
+        None => Ok((None, s.parse().map_err(bad)?)),
+    }
+}
+
+fn one_field<'l, I: Iterator<Item = &'l str>>(mut fields: I) -> Result<&'l str, String> {
+    match (fields.next(), fields.next()) {
+        (Some(field), None) => Ok(field),
+        _ => Err("expected one field".into()),
+    }
+}
+
+/// Split `s` at the first `sep`
+fn split_field(s: &str, sep: char) -> Option<(&str, &str)> {
+    let mut parts = s.splitn(2, sep);
+    Some((parts.next()?, parts.next()?))
+}
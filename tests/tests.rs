@@ -1,13 +1,23 @@
-use std::io::Cursor;
+#![cfg(feature = "std")]
+// the tests keep their original style
+#![allow(clippy::expect_fun_call, clippy::assertions_on_constants)]
+
+use std::io::{Cursor, Read};
+use vpk0::format::VpkMethod;
 use vpk0::LzssBackend::{self, *};
 
 const LOGO: &[u8] = include_bytes!("small-logo.png");
-const BACKENDS: &[LzssBackend] = &[Brute, Kmp, KmpAhead];
+const BACKENDS: &[LzssBackend] = &[Brute, Kmp, KmpAhead, HashChain { depth: 64 }, BinaryTree];
 const VPK_METHOD0: &[u8] = include_bytes!("method0.vpk0");
 const RAW_METHOD0: &[u8] = include_bytes!("method0-orig.bin");
 const VPK_METHOD1: &[u8] = include_bytes!("method1.vpk0");
 const RAW_METHOD1: &[u8] = include_bytes!("method1-orig.bin");
 const NOREPT_MESG: &[u8] = b"abcdefgh12345";
+// claims 4 GiB of output, then copies back 4 GiB twice
+const BOMB: &[u8] = &[
+    b'v', b'p', b'k', b'0', 0xff, 0xff, 0xff, 0xff, 0x00, // header
+    0x00, 0xc4, 0x12, 0x0f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x80,
+];
 
 #[test]
 fn decode_method0() {
@@ -145,6 +155,1102 @@ fn match_method1() {
     assert_eq!(compressed, VPK_METHOD1);
 }
 
+#[test]
+fn match_method0_trees() {
+    let compressed = vpk0::Encoder::for_bytes(RAW_METHOD0)
+        .one_sample()
+        .lzss_backend(Brute)
+        .tree_mode(vpk0::TreeMode::Nintendo)
+        .encode_to_vec()
+        .unwrap();
+
+    assert_eq!(compressed, VPK_METHOD0);
+}
+
+#[test]
+fn match_method1_trees() {
+    let compressed = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .two_sample()
+        .lzss_backend(Brute)
+        .tree_mode(vpk0::TreeMode::Nintendo)
+        .encode_to_vec()
+        .unwrap();
+
+    assert_eq!(compressed, VPK_METHOD1);
+}
+
+#[test]
+fn encode_deterministic() {
+    for &method in &[VpkMethod::OneSample, VpkMethod::TwoSample] {
+        let encode = || {
+            vpk0::Encoder::for_bytes(RAW_METHOD1)
+                .method(method)
+                .lzss_backend(Kmp)
+                .encode_to_vec()
+                .unwrap()
+        };
+
+        let first = encode();
+        for _ in 0..4 {
+            assert_eq!(
+                encode(),
+                first,
+                "encoding with {:?} is deterministic",
+                method
+            );
+        }
+    }
+}
+
+#[test]
+fn encode_lazy_matching() {
+    use vpk0::{errors::VpkError, LazyMatching, LazyPolicy, MatchCandidates};
+
+    // only use matches that start at the current byte, and are at least 8 bytes long
+    struct LongOnly;
+    impl LazyPolicy for LongOnly {
+        fn depth(&self) -> usize {
+            1
+        }
+
+        fn choose(&mut self, matches: &mut MatchCandidates<'_>) -> Option<usize> {
+            matches.length(0).filter(|&len| len >= 8).map(|_| 0)
+        }
+    }
+
+    let encode = |lazy| {
+        vpk0::Encoder::for_bytes(RAW_METHOD1)
+            .two_sample()
+            .lazy_matching(lazy)
+            .encode_to_vec()
+            .unwrap()
+    };
+
+    let nintendo = encode(LazyMatching::default());
+    assert_eq!(nintendo, encode(LazyMatching::Nintendo(10)));
+    assert_eq!(
+        encode(LazyMatching::Greedy),
+        encode(LazyMatching::Nintendo(1))
+    );
+
+    let mut sizes = Vec::new();
+    for &lazy in &[
+        LazyMatching::Greedy,
+        LazyMatching::OneStep,
+        LazyMatching::Nintendo(3),
+        LazyMatching::Nintendo(32),
+    ] {
+        let compressed = encode(lazy);
+        let decoded = vpk0::decode_bytes(&compressed).unwrap();
+        assert_eq!(decoded, RAW_METHOD1, "round trip with {:?}", lazy);
+        sizes.push(compressed.len());
+    }
+    assert!(sizes.windows(2).any(|w| w[0] != w[1]), "{:?}", sizes);
+
+    let mut policy = LongOnly;
+    let compressed = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .lazy_policy(&mut policy)
+        .encode_to_vec()
+        .unwrap();
+    assert!(compressed.len() > nintendo.len());
+    assert_eq!(vpk0::decode_bytes(&compressed).unwrap(), RAW_METHOD1);
+
+    // a depth of zero would never find a match, and a huge depth can't be buffered
+    for &depth in &[0, LazyMatching::MAX_DEPTH + 1, usize::MAX] {
+        let err = vpk0::Encoder::for_bytes(RAW_METHOD1)
+            .lazy_matching(LazyMatching::Nintendo(depth))
+            .encode_to_vec()
+            .unwrap_err();
+        assert!(
+            matches!(err, VpkError::InvalidLazyDepth(d) if d == depth),
+            "{}",
+            err
+        );
+    }
+    let deepest = encode(LazyMatching::Nintendo(LazyMatching::MAX_DEPTH));
+    assert_eq!(vpk0::decode_bytes(&deepest).unwrap(), RAW_METHOD1);
+}
+
+#[test]
+fn hash_chain_matches_brute() {
+    let inputs = &[
+        (RAW_METHOD0, VpkMethod::OneSample),
+        (RAW_METHOD1, VpkMethod::TwoSample),
+        (LOGO, VpkMethod::OneSample),
+    ];
+
+    for &(input, method) in inputs {
+        let encode = |backend| {
+            vpk0::Encoder::for_bytes(input)
+                .method(method)
+                .lzss_backend(backend)
+                .encode_to_vec()
+                .unwrap()
+        };
+
+        let brute = encode(Brute);
+        let chain = encode(HashChain { depth: usize::MAX });
+        assert_eq!(brute, chain, "same output as brute for {:?}", method);
+
+        let shallow = encode(HashChain { depth: 4 });
+        assert_eq!(vpk0::decode_bytes(&shallow).unwrap(), input);
+    }
+
+    assert_eq!(
+        vpk0::Encoder::for_bytes(RAW_METHOD0)
+            .lzss_backend(HashChain { depth: usize::MAX })
+            .with_lzss_settings(vpk0::LzssSettings::new(10, 6, 1))
+            .encode_to_vec()
+            .unwrap(),
+        vpk0::Encoder::for_bytes(RAW_METHOD0)
+            .lzss_backend(Brute)
+            .with_lzss_settings(vpk0::LzssSettings::new(10, 6, 1))
+            .encode_to_vec()
+            .unwrap(),
+        "same output as brute with a small window and short matches"
+    );
+}
+
+#[test]
+fn encode_optimal_parsing() {
+    let inputs = &[RAW_METHOD0, RAW_METHOD1, NOREPT_MESG, BOMB, b""];
+
+    for &input in inputs {
+        for &method in &[VpkMethod::OneSample, VpkMethod::TwoSample] {
+            let encode = |optimal| {
+                vpk0::Encoder::for_bytes(input)
+                    .method(method)
+                    .optimal_parsing(optimal)
+                    .encode_to_vec()
+                    .unwrap()
+            };
+
+            let default = encode(false);
+            let optimal = encode(true);
+            assert!(optimal.len() <= default.len(), "{:?}", method);
+            assert_eq!(vpk0::decode_bytes(&optimal).unwrap(), input);
+
+            let greedy = vpk0::Encoder::for_bytes(input)
+                .method(method)
+                .lazy_matching(vpk0::LazyMatching::Greedy)
+                .encode_to_vec()
+                .unwrap();
+            assert!(optimal.len() <= greedy.len(), "{:?}", method);
+        }
+    }
+
+    // user trees are kept, and only the sizes they contain are used
+    let compressed = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .optimal_parsing(true)
+        .with_offsets("(3, (7, 16))")
+        .with_lengths("(2, (4, 8))")
+        .encode_to_vec()
+        .unwrap();
+    let trees = vpk0::vpk_info(Cursor::new(&compressed)).unwrap().1;
+    assert_eq!(trees.offsets, "(3, (7, 16))");
+    assert_eq!(vpk0::decode_bytes(&compressed).unwrap(), RAW_METHOD1);
+
+    let default = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .two_sample()
+        .encode_to_vec()
+        .unwrap();
+    let optimal = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .two_sample()
+        .optimal_parsing(true)
+        .encode_to_vec()
+        .unwrap();
+    assert!(optimal.len() < default.len());
+}
+
+#[test]
+fn encode_max_code_length() {
+    // the deepest nesting of a tree string is its longest code
+    let depth = |tree: &str| {
+        tree.chars()
+            .scan(0i32, |d, c| {
+                *d += match c {
+                    '(' => 1,
+                    ')' => -1,
+                    _ => 0,
+                };
+                Some(*d)
+            })
+            .max()
+            .unwrap_or(0)
+    };
+
+    for &limit in &[0, 1, 2, 3] {
+        let compressed = vpk0::Encoder::for_bytes(RAW_METHOD1)
+            .max_code_length(limit)
+            .encode_to_vec()
+            .unwrap();
+        let trees = vpk0::vpk_info(Cursor::new(&compressed)).unwrap().1;
+        assert!(depth(&trees.offsets) <= limit as i32, "{}", trees.offsets);
+        assert!(depth(&trees.lengths) <= limit as i32, "{}", trees.lengths);
+        assert_eq!(vpk0::decode_bytes(&compressed).unwrap(), RAW_METHOD1);
+    }
+
+    let deep = format!("{}1{}", "(".repeat(40), ", 16)".repeat(40));
+    let err = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .with_offsets(&deep)
+        .encode_to_vec()
+        .unwrap_err();
+    assert!(
+        matches!(err, vpk0::errors::VpkError::BadUserTree(..)),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn extend_deep_user_tree() {
+    // grafting onto a tree with 32 bit codes must not make a code too long
+    let deep = format!("{}1{}", "(".repeat(32), ", 2)".repeat(32));
+    for &extend in &[false, true] {
+        let result = vpk0::Encoder::for_bytes(RAW_METHOD1)
+            .with_offsets(&deep)
+            .extend_trees(extend)
+            .encode_to_vec();
+        match result {
+            Ok(compressed) => {
+                assert!(extend);
+                assert_eq!(vpk0::decode_bytes(&compressed).unwrap(), RAW_METHOD1);
+            }
+            Err(err) => assert!(!extend, "{}", err),
+        }
+    }
+}
+
+#[test]
+fn encode_errors() {
+    use vpk0::errors::{TreeKind, VpkError};
+
+    // the offsets in the file need more than 5 bits
+    let err = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .with_offsets("(3, 5)")
+        .encode_to_vec()
+        .unwrap_err();
+    assert!(
+        matches!(err.kind(), VpkError::UserTreeTooSmall(_, 5)),
+        "{}",
+        err
+    );
+    assert_eq!(err.tree(), Some(TreeKind::Offsets));
+
+    let err = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .with_lengths("(1, 2)")
+        .encode_to_vec()
+        .unwrap_err();
+    assert_eq!(err.tree(), Some(TreeKind::Lengths));
+
+    struct BrokenLog;
+    impl std::io::Write for BrokenLog {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    for &optimal in &[false, true] {
+        let err = vpk0::Encoder::for_bytes(RAW_METHOD0)
+            .optimal_parsing(optimal)
+            .with_logging(&mut BrokenLog)
+            .encode_to_vec()
+            .unwrap_err();
+        assert!(matches!(err, VpkError::Log(..)), "{}", err);
+        assert_eq!(
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+    }
+}
+
+#[test]
+fn encode_extend_trees() {
+    let leaves = |tree: &str| -> Vec<u8> {
+        tree.split(|c: char| !c.is_ascii_digit())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().unwrap())
+            .collect()
+    };
+
+    let mut encoder = vpk0::Encoder::for_bytes(RAW_METHOD1);
+    let compressed = encoder
+        .with_offsets("(3, (7, 10))")
+        .with_lengths("(2, 3)")
+        .extend_trees(true)
+        .encode_to_vec()
+        .unwrap();
+    assert_eq!(vpk0::decode_bytes(&compressed).unwrap(), RAW_METHOD1);
+
+    let added = encoder.added_leaves().clone();
+    assert!(!added.offsets.is_empty());
+    assert!(!added.lengths.is_empty());
+
+    // the original leaves are kept, and the new ones are added
+    let trees = vpk0::vpk_info(Cursor::new(&compressed)).unwrap().1;
+    let mut expected = vec![3, 7, 10];
+    expected.extend(&added.offsets);
+    expected.sort_unstable();
+    let mut found = leaves(&trees.offsets);
+    found.sort_unstable();
+    assert_eq!(found, expected);
+
+    // trees that are wide enough are not changed
+    let plain = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .with_offsets("(3, (7, 16))")
+        .encode_to_vec()
+        .unwrap();
+    let mut encoder = vpk0::Encoder::for_bytes(RAW_METHOD1);
+    let extended = encoder
+        .with_offsets("(3, (7, 16))")
+        .extend_trees(true)
+        .encode_to_vec()
+        .unwrap();
+    assert_eq!(plain, extended);
+    assert_eq!(encoder.added_leaves(), &vpk0::AddedLeaves::default());
+}
+
+#[test]
+fn huffman_tree_type() {
+    use vpk0::format::HuffmanTree;
+
+    for &vpk in &[VPK_METHOD0, VPK_METHOD1] {
+        let info = vpk0::vpk_info(Cursor::new(vpk)).unwrap().1;
+        let trees = vpk0::Decoder::for_bytes(vpk).huffman_trees().unwrap();
+        assert_eq!(trees.offsets.to_string(), info.offsets);
+        assert_eq!(trees.lengths.to_string(), info.lengths);
+        assert_eq!(info.offsets.parse::<HuffmanTree>().unwrap(), trees.offsets);
+
+        // the offsets tree starts right after the nine byte header
+        let from_file = HuffmanTree::from_bits(&vpk[9..]).unwrap();
+        assert_eq!(from_file, trees.offsets);
+        let bits = trees.offsets.to_bits();
+        let whole_bytes = trees.offsets.bit_len() / 8;
+        assert_eq!(bits[..whole_bytes], vpk[9..9 + whole_bytes]);
+        assert_eq!(HuffmanTree::from_bits(&bits).unwrap(), trees.offsets);
+    }
+
+    // trees can be used directly by the encoder
+    let trees = vpk0::Decoder::for_bytes(VPK_METHOD0)
+        .huffman_trees()
+        .unwrap();
+    let compressed = vpk0::Encoder::for_bytes(RAW_METHOD0)
+        .with_offsets_tree(&trees.offsets)
+        .with_lengths_tree(&trees.lengths)
+        .encode_to_vec()
+        .unwrap();
+    assert_eq!(compressed, VPK_METHOD0);
+
+    let tree =
+        HuffmanTree::from_frequencies(vec![(2, 100), (4, 50), (9, 1)], vpk0::TreeMode::Nintendo);
+    let leaves = tree.leaves();
+    assert!(leaves.iter().any(|l| l.bit_size == 9));
+    let expected: u64 = [3usize, 15, 500]
+        .iter()
+        .map(|&v| tree.leaf_for(v).unwrap())
+        .map(|l| (l.code_len + l.bit_size as u32) as u64)
+        .sum();
+    assert_eq!(tree.cost(vec![3, 15, 500]), Some(expected));
+    assert_eq!(tree.cost(vec![3, 1024]), None);
+
+    // a tree where each node adds one more leaf has a code as long as the number of nodes
+    let chain = |nodes: usize| {
+        let mut bits = vec![false; 9];
+        for _ in 0..nodes {
+            bits.extend(&[false, false, false, false, false, false, false, false, true]);
+            bits.push(true);
+        }
+        bits.push(true);
+        let mut bytes = vec![0u8; (bits.len() + 7) / 8];
+        for (i, &bit) in bits.iter().enumerate() {
+            bytes[i / 8] |= (bit as u8) << (7 - i % 8);
+        }
+        bytes
+    };
+    assert!(HuffmanTree::from_bits(&chain(32)).is_ok());
+    let err = HuffmanTree::from_bits(&chain(33)).unwrap_err();
+    assert!(
+        matches!(err, vpk0::errors::VpkError::TreeTooDeep(33)),
+        "{}",
+        err
+    );
+
+    // the encoder accepts every tree with codes of up to 32 bits
+    let deep = format!("{}16{}", "(".repeat(32), ", 16)".repeat(32));
+    let tree: HuffmanTree = deep.parse().unwrap();
+    let compressed = vpk0::Encoder::for_bytes(RAW_METHOD0)
+        .with_offsets_tree(&tree)
+        .encode_to_vec()
+        .unwrap();
+    assert_eq!(vpk0::decode_bytes(&compressed).unwrap(), RAW_METHOD0);
+}
+
+#[test]
+fn encode_tokens() {
+    use vpk0::errors::VpkError;
+    use vpk0::format::{OffsetSample, Token};
+
+    let cases = [
+        (RAW_METHOD0, VPK_METHOD0, VpkMethod::OneSample),
+        (RAW_METHOD1, VPK_METHOD1, VpkMethod::TwoSample),
+    ];
+    for &(raw, vpk, method) in &cases {
+        let tokens = vpk0::Encoder::for_bytes(raw)
+            .method(method)
+            .lzss_backend(Brute)
+            .encode_to_tokens()
+            .unwrap();
+        assert_eq!(tokens.iter().map(Token::len).sum::<usize>(), raw.len());
+
+        // writing the tokens with the original trees gives back the original file
+        let trees = vpk0::Decoder::for_bytes(vpk).huffman_trees().unwrap();
+        let mut out = Vec::new();
+        vpk0::write_tokens(
+            &mut out,
+            method,
+            &tokens,
+            Some(&trees.offsets),
+            Some(&trees.lengths),
+        )
+        .unwrap();
+        assert_eq!(out, vpk);
+
+        // or build new trees from the tokens
+        let mut out = Vec::new();
+        vpk0::write_tokens(&mut out, method, &tokens, None, None).unwrap();
+        assert_eq!(vpk0::decode_bytes(&out).unwrap(), raw);
+    }
+
+    let write = |method, tokens: &[Token]| {
+        vpk0::write_tokens(Vec::new(), method, tokens, None, None).unwrap_err()
+    };
+    let lit = Token::Literal(0);
+
+    let err = write(
+        VpkMethod::TwoSample,
+        &[
+            lit,
+            Token::Match {
+                offset: 1,
+                length: 3,
+            },
+        ],
+    );
+    assert!(
+        matches!(err, VpkError::WrongTokenMethod(VpkMethod::TwoSample)),
+        "{}",
+        err
+    );
+
+    let offset = OffsetSample::Two(3, 0);
+    let err = write(
+        VpkMethod::TwoSample,
+        &[lit, Token::TwoSampleMatch { offset, length: 3 }],
+    );
+    assert!(
+        matches!(err, VpkError::InvalidOffsetSample(o) if o == offset),
+        "{}",
+        err
+    );
+
+    let err = write(
+        VpkMethod::OneSample,
+        &[
+            lit,
+            lit,
+            Token::Match {
+                offset: 3,
+                length: 3,
+            },
+        ],
+    );
+    assert!(matches!(err, VpkError::BadLookBack(3, 2)), "{}", err);
+}
+
+#[test]
+fn reencode_edited() {
+    for &vpk in &[VPK_METHOD0, VPK_METHOD1] {
+        let original = vpk0::Decoder::for_bytes(vpk).decode_tokens().unwrap();
+        assert_eq!(original.data.len(), original.header.size as usize);
+
+        // without changes, the original file is written
+        let unchanged = vpk0::Encoder::for_bytes(&original.data)
+            .reencode_to_vec(&original)
+            .unwrap();
+        assert_eq!(unchanged, vpk);
+
+        let mut replaced = original.data.clone();
+        replaced[600..610].copy_from_slice(b"0123456789");
+        let mut inserted = original.data.clone();
+        inserted.splice(800..800, b"inserted bytes".iter().copied());
+        let mut removed = original.data.clone();
+        removed.drain(700..730);
+
+        for edited in &[replaced, inserted, removed] {
+            for &optimal in &[false, true] {
+                let compressed = vpk0::Encoder::for_bytes(edited)
+                    .extend_trees(true)
+                    .optimal_parsing(optimal)
+                    .reencode_to_vec(&original)
+                    .unwrap();
+                let decoded = vpk0::Decoder::for_bytes(&compressed)
+                    .decode_tokens()
+                    .unwrap();
+                assert_eq!(&decoded.data, edited);
+                assert_eq!(decoded.header.method, original.header.method);
+
+                // the tokens at the start and end are reused
+                assert_eq!(decoded.tokens[..10], original.tokens[..10]);
+                let (end, old_end) = (decoded.tokens.len() - 10, original.tokens.len() - 10);
+                assert_eq!(decoded.tokens[end..], original.tokens[old_end..]);
+            }
+        }
+    }
+}
+
+#[test]
+fn infer_settings() {
+    use vpk0::format::Token;
+    use vpk0::{LazyMatching, LzssSettings, TreeMode};
+
+    for &vpk in &[VPK_METHOD0, VPK_METHOD1] {
+        let inferred = vpk0::infer_settings(vpk).unwrap();
+        assert!(inferred.exact);
+        assert_eq!(inferred.mismatch, None);
+        assert_eq!(inferred.settings, LzssSettings::default());
+        assert_eq!(inferred.lazy, LazyMatching::default());
+        assert_eq!(inferred.tree_mode, Some(TreeMode::Nintendo));
+    }
+
+    // "abc" is repeated with a literal instead of a copy-back, which no encoder does
+    let data = b"abcabcabcabc";
+    let tokens: Vec<_> = data[..6]
+        .iter()
+        .map(|&b| Token::Literal(b))
+        .chain(Some(Token::Match {
+            offset: 6,
+            length: 6,
+        }))
+        .collect();
+    let mut compressed = Vec::new();
+    vpk0::write_tokens(&mut compressed, VpkMethod::OneSample, &tokens, None, None).unwrap();
+
+    let inferred = vpk0::infer_settings(&compressed).unwrap();
+    assert!(!inferred.exact);
+    let mismatch = inferred.mismatch.unwrap();
+    assert_eq!(mismatch.index, 3);
+    assert_eq!(mismatch.expected, Some(Token::Literal(b'a')));
+    assert!(matches!(
+        mismatch.found,
+        Some(Token::Match { offset: 3, .. })
+    ));
+}
+
+#[test]
+fn diff_files() {
+    use vpk0::LazyMatching;
+
+    let same = vpk0::diff(VPK_METHOD1, VPK_METHOD1).unwrap();
+    assert!(same.is_identical());
+    assert_eq!(same.bit_difference(), 0);
+    let costs = same.costs.0;
+    assert_eq!(
+        costs.total,
+        72 + costs.trees + costs.literals + costs.matches
+    );
+    assert_eq!((costs.total + 7) / 8, VPK_METHOD1.len() as u64);
+
+    let greedy = vpk0::Encoder::for_bytes(RAW_METHOD1)
+        .two_sample()
+        .lzss_backend(BinaryTree)
+        .lazy_matching(LazyMatching::Greedy)
+        .encode_to_vec()
+        .unwrap();
+    let diff = vpk0::diff(VPK_METHOD1, &greedy[..]).unwrap();
+    assert!(!diff.is_identical());
+    assert_eq!(diff.header, None);
+    let first = diff.first_difference.unwrap();
+    let (left, right) = (first.left.unwrap(), first.right.unwrap());
+    assert_ne!(left.token, right.token);
+
+    // the tokens before the difference are the same
+    let nintendo = vpk0::Decoder::for_bytes(VPK_METHOD1)
+        .decode_tokens()
+        .unwrap();
+    let tokens = &nintendo.tokens[..first.index];
+    assert_eq!(
+        tokens.iter().map(|t| t.len()).sum::<usize>(),
+        first.position
+    );
+    assert_eq!(nintendo.tokens[first.index], left.token);
+    let report = diff.to_string();
+    assert!(
+        report.contains(&format!("token {} at", first.index)),
+        "{}",
+        report
+    );
+
+    let one_sample = vpk0::encode_bytes(RAW_METHOD1).unwrap();
+    let diff = vpk0::diff(VPK_METHOD1, &one_sample[..]).unwrap();
+    let (left, right) = diff.header.unwrap();
+    assert_eq!(left.method, VpkMethod::TwoSample);
+    assert_eq!(right.method, VpkMethod::OneSample);
+    assert!(diff.trees.is_some());
+    assert_eq!(
+        diff.bit_difference(),
+        diff.costs.1.total as i64 - diff.costs.0.total as i64
+    );
+}
+
+#[test]
+fn listing_round_trip() {
+    use vpk0::errors::VpkError;
+
+    for &file in &[VPK_METHOD0, VPK_METHOD1] {
+        let listing = vpk0::disassemble(file).unwrap();
+        assert_eq!(vpk0::assemble(&listing).unwrap(), file);
+    }
+
+    // values without codes use the smallest leaf, and padding defaults to zeros
+    let listing = "header method=0 size=6\n\
+        offsets 000000010 1 ; (2)\n\
+        lengths 000000011 1 ; (3)\n\
+        lit 61\n\
+        lit 62\n\
+        copy off=2 len=4\n";
+    let assembled = vpk0::assemble(listing).unwrap();
+    assert_eq!(vpk0::decode_bytes(&assembled).unwrap(), b"ababab");
+    let round_trip = vpk0::disassemble(&assembled).unwrap();
+    assert!(round_trip.contains("copy off=:2 len=:4"), "{}", round_trip);
+
+    // non-zero padding and trailing bytes are kept
+    let zeros = round_trip
+        .lines()
+        .find_map(|line| line.strip_prefix("padding "))
+        .expect("the tokens don't fill the last byte");
+    let ones = "1".repeat(zeros.len());
+    let padded_listing = format!("{}padding {}\ntrailing de ad be ef\n", listing, ones);
+    let padded = vpk0::assemble(&padded_listing).unwrap();
+    let last = assembled.len() - 1;
+    assert_eq!(&padded[..last], &assembled[..last]);
+    assert_eq!(padded[last], assembled[last] | ((1 << ones.len()) - 1));
+    assert_eq!(&padded[last + 1..], &[0xde, 0xad, 0xbe, 0xef]);
+    assert_eq!(vpk0::decode_bytes(&padded).unwrap(), b"ababab");
+    let listing_again = vpk0::disassemble(&padded).unwrap();
+    assert!(
+        listing_again.contains(&format!("padding {}\ntrailing de ad be ef", ones)),
+        "{}",
+        listing_again
+    );
+    assert_eq!(vpk0::assemble(&listing_again).unwrap(), padded);
+
+    let bad = listing.replace("off=2", "off=4");
+    let err = vpk0::assemble(&bad).unwrap_err();
+    assert!(matches!(err, VpkError::BadListing(6, _)), "{}", err);
+
+    // values are at most 32 bits, so wider leaves are rejected
+    let wide = "header method=0 size=10\n\
+        offsets 0110010001\n\
+        lengths 0110010001\n\
+        lit 61\n\
+        copy off=1 len=5\n";
+    let err = vpk0::assemble(wide).unwrap_err();
+    assert!(matches!(err, VpkError::BadListing(2, _)), "{}", err);
+}
+
+#[test]
+fn decode_into_method0() {
+    let mut buf = vec![0u8; RAW_METHOD0.len()];
+    let size = vpk0::Decoder::for_bytes(VPK_METHOD0)
+        .decode_into(&mut buf)
+        .expect("working decode into buffer");
+
+    assert_eq!(size, RAW_METHOD0.len());
+    assert_eq!(buf, RAW_METHOD0, "decoding method 0 into buffer");
+}
+
+#[test]
+fn decode_into_method1() {
+    let mut buf = vec![0u8; RAW_METHOD1.len() + 16];
+    let size = vpk0::Decoder::for_bytes(VPK_METHOD1)
+        .decode_into(&mut buf)
+        .expect("working decode into buffer");
+
+    assert_eq!(&buf[..size], RAW_METHOD1, "decoding method 1 into buffer");
+}
+
+#[test]
+fn decode_into_small_buffer() {
+    let mut buf = vec![0u8; RAW_METHOD0.len() - 1];
+    let result = vpk0::Decoder::for_bytes(VPK_METHOD0).decode_into(&mut buf);
+
+    assert!(
+        matches!(result, Err(vpk0::errors::VpkError::BufferTooSmall(..))),
+        "Expected error when decoding into a too small buffer"
+    );
+}
+
+#[test]
+fn decode_events_method1() {
+    use vpk0::{format::OffsetSample, DecodeEvent};
+
+    let mut rebuilt = Vec::new();
+    let mut last_bit = 0;
+    let mut two_samples = 0;
+    let decoded = vpk0::Decoder::for_bytes(VPK_METHOD1)
+        .decode_with_events(|event| match event {
+            DecodeEvent::Header(header) => assert_eq!(header.size as usize, RAW_METHOD1.len()),
+            DecodeEvent::Trees(trees) => assert_eq!(trees, vpk0::vpk_info(VPK_METHOD1).unwrap().1),
+            DecodeEvent::Literal {
+                position,
+                bit_offset,
+                byte,
+            } => {
+                assert_eq!(position, rebuilt.len());
+                assert!(bit_offset > last_bit);
+                last_bit = bit_offset;
+                rebuilt.push(byte);
+            }
+            DecodeEvent::CopyBack {
+                position,
+                bit_offset,
+                length,
+                sample,
+                data,
+                ..
+            } => {
+                assert_eq!(position, rebuilt.len());
+                assert_eq!(length, data.len());
+                assert!(bit_offset > last_bit);
+                last_bit = bit_offset;
+                if let OffsetSample::Two(..) = sample {
+                    two_samples += 1;
+                }
+                rebuilt.extend_from_slice(data);
+            }
+            _ => {}
+        })
+        .expect("working decode with events");
+
+    assert_eq!(decoded, RAW_METHOD1);
+    assert_eq!(rebuilt, RAW_METHOD1, "rebuilding output from events");
+    assert!(
+        two_samples > 0,
+        "Expected two sample offsets in method 1 file"
+    );
+}
+
+#[test]
+fn decode_text_log() {
+    let mut log = Vec::new();
+    let decoded = vpk0::Decoder::for_bytes(VPK_METHOD0)
+        .with_logging(&mut log)
+        .decode()
+        .unwrap();
+    let log = String::from_utf8(log).unwrap();
+
+    assert_eq!(decoded, RAW_METHOD0);
+    assert!(log.starts_with("# Header"));
+    assert!(log.contains("###> VpkTree { entries: ["));
+    assert!(log.contains("Encoded [Copyback]"));
+    assert!(log.contains("Uncoded"));
+}
+
+#[test]
+fn decode_with_limits() {
+    use vpk0::errors::VpkError;
+
+    for &(vpk, raw) in &[(VPK_METHOD0, RAW_METHOD0), (VPK_METHOD1, RAW_METHOD1)] {
+        let decoded = vpk0::Decoder::for_bytes(vpk)
+            .max_output_size(raw.len())
+            .max_ratio(64)
+            .lazy_allocation(true)
+            .decode()
+            .expect("valid files are within the limits");
+        assert_eq!(decoded, raw);
+    }
+
+    let too_large = vpk0::Decoder::for_bytes(BOMB)
+        .max_output_size(1 << 20)
+        .decode();
+    assert!(
+        matches!(too_large, Err(VpkError::OutputTooLarge(..))),
+        "Expected size limit error, got {:?}",
+        too_large
+    );
+
+    let too_compressed = vpk0::Decoder::for_bytes(BOMB)
+        .max_ratio(64)
+        .lazy_allocation(true)
+        .decode();
+    assert!(
+        matches!(
+            too_compressed.as_ref().map_err(VpkError::kind),
+            Err(VpkError::RatioExceeded(..))
+        ),
+        "Expected ratio limit error, got {:?}",
+        too_compressed
+    );
+}
+
+#[test]
+fn stream_decode_with_limits() {
+    use vpk0::errors::VpkError;
+
+    // a 4 GiB header with an offsets tree that can move back 4 GiB
+    let huge = vpk_bits(u32::MAX, "0 00100000 1 1 0 01100001 0 01100010");
+    let mut reader = vpk0::VpkReader::new(&huge[..]).unwrap();
+    let mut start = [0u8; 2];
+    reader.read_exact(&mut start).unwrap();
+    assert_eq!(&start, b"ab");
+
+    let mut decoder = vpk0::Decoder::for_bytes(&huge);
+    decoder.max_output_size(1 << 20);
+    let too_large = decoder.into_reader();
+    assert!(
+        matches!(too_large, Err(VpkError::OutputTooLarge(..))),
+        "Expected size limit error"
+    );
+
+    let mut decoder = vpk0::Decoder::for_bytes(BOMB);
+    decoder.max_ratio(64);
+    let mut reader = decoder.into_reader().unwrap();
+    let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+    let err = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<VpkError>())
+        .expect("VpkError inside io::Error");
+    assert!(
+        matches!(err.kind(), VpkError::RatioExceeded(..)),
+        "{:?}",
+        err
+    );
+}
+
+#[test]
+fn decode_strict() {
+    use vpk0::errors::VpkError;
+
+    for &(vpk, raw) in &[(VPK_METHOD0, RAW_METHOD0), (VPK_METHOD1, RAW_METHOD1)] {
+        let decoded = vpk0::Decoder::for_bytes(vpk)
+            .strict(true)
+            .decode()
+            .expect("original files pass strict validation");
+        assert_eq!(decoded, raw);
+    }
+
+    let mut trailing = VPK_METHOD0.to_vec();
+    trailing.push(0);
+    let wide_leaves = format!("{}1", "0 00100000 ".repeat(34) + &"1 ".repeat(33));
+
+    let cases: &[(&str, Vec<u8>)] = &[
+        ("trailing data", trailing),
+        // literals 'A' and 'B', then padding of 0001
+        (
+            "non-zero padding",
+            vpk_bits(2, "1 1 0 01000001 0 01000010 0001"),
+        ),
+        // literals 'A' and 'B', then copy three bytes from one back
+        (
+            "overshoot",
+            vpk_bits(3, "0 00000001 1 0 00000010 1 0 01000001 0 01000010 1 1 11"),
+        ),
+        ("wide leaf", vpk_bits(1, "0 00101000 1 1 0 01000001")),
+        (
+            "too many entries",
+            vpk_bits(1, &format!("{} 1 0 01000001", wide_leaves)),
+        ),
+    ];
+
+    for (name, vpk) in cases {
+        vpk0::Decoder::for_bytes(vpk)
+            .decode()
+            .unwrap_or_else(|e| panic!("non-strict decode of {}: {}", name, e));
+
+        let strict = vpk0::Decoder::for_bytes(vpk).strict(true).decode();
+        let expected = matches!(
+            (*name, strict.as_ref().map_err(VpkError::kind)),
+            ("trailing data", Err(VpkError::TrailingData))
+                | ("non-zero padding", Err(VpkError::NonZeroPadding(0b0001)))
+                | ("overshoot", Err(VpkError::CopyOvershoot(5, 3)))
+                | ("wide leaf", Err(VpkError::LeafTooWide(40)))
+                | ("too many entries", Err(VpkError::TooManyTreeEntries(67)))
+        );
+        assert!(
+            expected,
+            "unexpected strict result for {}: {:?}",
+            name, strict
+        );
+    }
+}
+
+#[test]
+fn decode_error_locations() {
+    use vpk0::errors::{ErrorLocation, TreeKind, VpkError};
+
+    // literal 'A', then a copy-back of two bytes from three bytes back
+    let bad_lookback = vpk_bits(4, "0 00000010 1 0 00000010 1 0 01000001 1 11 01");
+    let location = ErrorLocation {
+        bit_offset: 9 * 8 + 20 + 9 + 3,
+        output_position: 1,
+        token_index: 1,
+    };
+
+    let err = vpk0::decode_bytes(&bad_lookback).unwrap_err();
+    assert!(
+        matches!(err.kind(), VpkError::BadLookBack(3, 1)),
+        "{:?}",
+        err
+    );
+    assert_eq!(err.location(), Some(location));
+    assert_eq!(err.bit_offset(), Some(location.bit_offset));
+    assert_eq!(err.output_position(), Some(1));
+    assert_eq!(err.token_index(), Some(1));
+    assert_eq!(err.tree(), None);
+
+    let mut reader = vpk0::VpkReader::new(&bad_lookback[..]).unwrap();
+    let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+    let err = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<VpkError>())
+        .expect("VpkError inside io::Error");
+    assert_eq!(err.location(), Some(location));
+
+    // the lengths tree is cut off in the middle of a leaf
+    let bad_tree = vpk_bits(1, "0 00000001 1 0 0000");
+    let err = vpk0::decode_bytes(&bad_tree).unwrap_err();
+    assert!(matches!(err.kind(), VpkError::UnexpectedEof), "{:?}", err);
+    assert_eq!(err.tree(), Some(TreeKind::Lengths));
+    assert_eq!(err.location(), None);
+}
+
+#[test]
+fn decode_partial() {
+    use vpk0::errors::VpkError;
+
+    for &(vpk, raw) in &[(VPK_METHOD0, RAW_METHOD0), (VPK_METHOD1, RAW_METHOD1)] {
+        let complete = vpk0::Decoder::for_bytes(vpk).decode_partial();
+        assert!(complete.is_complete());
+        assert_eq!((complete.bit_offset + 7) / 8, vpk.len() as u64);
+        assert_eq!(complete.into_result().unwrap(), raw);
+
+        let truncated = &vpk[..vpk.len() / 2];
+        let partial = vpk0::Decoder::for_bytes(truncated).decode_partial();
+        let err = partial
+            .error
+            .as_ref()
+            .expect("truncated file fails to decode");
+        assert!(matches!(err.kind(), VpkError::UnexpectedEof), "{:?}", err);
+        assert_eq!(err.output_position(), Some(partial.data.len()));
+        assert!(partial.bit_offset <= truncated.len() as u64 * 8);
+        assert!(partial.data.len() > raw.len() / 4);
+        assert_eq!(&raw[..partial.data.len()], &partial.data[..]);
+    }
+
+    let bad_file = include_bytes!("bad-file.vpk0");
+    let partial = vpk0::Decoder::for_bytes(bad_file).decode_partial();
+    assert!(!partial.is_complete());
+    assert_eq!(
+        partial.error.as_ref().and_then(VpkError::output_position),
+        Some(partial.data.len())
+    );
+}
+
+#[test]
+fn decode_concatenated() {
+    let mut data = VPK_METHOD0.to_vec();
+    data.extend_from_slice(VPK_METHOD1);
+    data.extend_from_slice(b"trailing");
+
+    let mut first = vpk0::Decoder::for_reader(Cursor::new(&data[..]));
+    assert_eq!(first.decode().unwrap(), RAW_METHOD0);
+    assert_eq!((first.input_bits() + 7) / 8, first.input_bytes());
+    assert_eq!(first.input_bytes(), VPK_METHOD0.len() as u64);
+
+    let rdr = first.into_inner();
+    assert_eq!(rdr.position(), VPK_METHOD0.len() as u64);
+
+    let mut second = vpk0::Decoder::for_reader(rdr);
+    assert_eq!(second.decode().unwrap(), RAW_METHOD1);
+    assert_eq!(second.input_bytes(), VPK_METHOD1.len() as u64);
+
+    let mut rest = Vec::new();
+    second.into_inner().read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"trailing");
+}
+
+#[test]
+fn stream_decode_method0() {
+    let mut reader = vpk0::VpkReader::new(VPK_METHOD0).expect("valid vpk0 header and trees");
+    let mut decoded = Vec::new();
+    let mut chunk = [0u8; 7];
+    loop {
+        match reader.read(&mut chunk).expect("working stream decode") {
+            0 => break,
+            n => decoded.extend_from_slice(&chunk[..n]),
+        }
+    }
+
+    assert_eq!(decoded, RAW_METHOD0, "stream decoding method 0");
+}
+
+#[test]
+fn stream_decode_method1() {
+    let mut reader = vpk0::VpkReader::new(Cursor::new(VPK_METHOD1)).unwrap();
+    let mut decoded = Vec::new();
+    reader
+        .read_to_end(&mut decoded)
+        .expect("working stream decode");
+
+    assert_eq!(decoded, RAW_METHOD1, "stream decoding method 1");
+}
+
+#[test]
+fn stream_decode_bad_file() {
+    let bad_file = include_bytes!("bad-file.vpk0");
+    let mut reader = vpk0::VpkReader::new(&bad_file[..]).unwrap();
+    let mut decoded = Vec::new();
+
+    assert!(
+        reader.read_to_end(&mut decoded).is_err(),
+        "Expected error when stream decoding bad file"
+    );
+}
+
+#[test]
+fn decode_degenerate_and_deep_trees() {
+    // single leaf trees have zero-length codes, and the deep tree is
+    // longer than the decoder's lookup table
+    let trees = &[
+        ("16", "8"),
+        (
+            "(1, (2, (3, (4, (5, (6, (7, (8, (9, (10, (11, (12, 16))))))))))))",
+            "(2, (4, 8))",
+        ),
+    ];
+
+    for &(offsets, lengths) in trees {
+        let compressed = vpk0::Encoder::for_bytes(LOGO)
+            .with_offsets(offsets)
+            .with_lengths(lengths)
+            .encode_to_vec()
+            .unwrap();
+
+        let mut decoder = vpk0::Decoder::for_bytes(&compressed);
+        assert_eq!(decoder.trees().unwrap().offsets, offsets);
+        assert_eq!(decoder.decode().unwrap(), LOGO, "decoding {}", offsets);
+
+        let mut decoded = Vec::new();
+        vpk0::VpkReader::new(&compressed[..])
+            .and_then(|mut r| r.read_to_end(&mut decoded).map_err(Into::into))
+            .unwrap();
+        assert_eq!(decoded, LOGO, "stream decoding {}", offsets);
+    }
+}
+
 #[test]
 fn decode_bad_file() {
     let bad_file = include_bytes!("bad-file.vpk0");
@@ -163,3 +1269,23 @@ fn decode_bad_file() {
         }
     };
 }
+
+/// Create a one sample `vpk0` file of `size` bytes from a string of
+/// whitespace separated bits for the trees and encoded data
+fn vpk_bits(size: u32, bits: &str) -> Vec<u8> {
+    let mut out = b"vpk0".to_vec();
+    out.extend_from_slice(&size.to_be_bytes());
+    out.push(0);
+
+    let bits: Vec<u8> = bits.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    for byte in bits.chunks(8) {
+        let val = byte
+            .iter()
+            .chain(std::iter::repeat(&b'0'))
+            .take(8)
+            .fold(0, |acc, &b| (acc << 1) | (b == b'1') as u8);
+        out.push(val);
+    }
+
+    out
+}